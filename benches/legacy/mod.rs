@@ -0,0 +1,176 @@
+//! JSON response building used before `processor::write_response`,
+//! kept as the baseline for the response encoding benchmark.
+//! It converts the rows to `serde_json` values and serializes them afterwards.
+
+use std::pin::Pin;
+
+use anyhow::Context;
+use async_stream::try_stream;
+use datafusion::{
+    arrow::{json::writer::record_batches_to_json_rows, record_batch::RecordBatch},
+    error::DataFusionError,
+    physical_plan::SendableRecordBatchStream,
+};
+use futures::{stream::Peekable, Stream, StreamExt};
+use itertools::Itertools;
+use serde_json::{map::Map as JsonMap, Value};
+
+use subsquid_worker::query::eth::BatchRequest;
+
+/// Marks the block headers matching the query's block requests
+const SELECTED_COLUMN: &str = "_selected";
+
+pub async fn build_response(
+    query: &BatchRequest,
+    headers: SendableRecordBatchStream,
+    transactions: Option<SendableRecordBatchStream>,
+    logs: Option<SendableRecordBatchStream>,
+) -> anyhow::Result<Vec<u8>> {
+    let blocks = convert_to_json(headers);
+    let transactions = transactions.map(convert_to_json);
+    let logs = logs.map(convert_to_json);
+    let rows = collect_result(build_rows(query, blocks, transactions, logs)).await?;
+    Ok(serde_json::to_vec(&rows)?)
+}
+
+fn convert_to_json(
+    stream: impl Stream<Item = Result<RecordBatch, DataFusionError>>,
+) -> impl Stream<Item = Result<JsonMap<String, Value>, DataFusionError>> {
+    stream.flat_map(|record_batch| {
+        let entries = match record_batch
+            .and_then(|batch| record_batches_to_json_rows(&[&batch]).map_err(From::from))
+        {
+            Ok(vec) => vec.into_iter().map(Ok).collect_vec(),
+            Err(e) => vec![Err(e)],
+        };
+        futures::stream::iter(entries)
+    })
+}
+
+fn build_rows<'l>(
+    query: &'l BatchRequest,
+    headers: impl Stream<Item = Result<JsonMap<String, Value>, DataFusionError>> + Unpin + 'l,
+    transactions: Option<impl Stream<Item = Result<JsonMap<String, Value>, DataFusionError>> + 'l>,
+    logs: Option<impl Stream<Item = Result<JsonMap<String, Value>, DataFusionError>> + 'l>,
+) -> impl Stream<Item = anyhow::Result<Value>> + 'l {
+    let include_tx = transactions.is_some();
+    let include_logs = logs.is_some();
+    let mut transactions = transactions.map(|stream| Box::pin(stream.peekable()));
+    let mut logs = logs.map(|stream| Box::pin(stream.peekable()));
+
+    async fn block_rows<S: Stream<Item = Result<JsonMap<String, Value>, DataFusionError>>>(
+        stream: &mut Option<Pin<Box<Peekable<S>>>>,
+        block_number: u64,
+    ) -> Result<Vec<JsonMap<String, Value>>, DataFusionError> {
+        if let Some(stream) = stream.as_mut() {
+            consume_while(stream.as_mut(), |tx: &JsonMap<String, Value>| {
+                tx.get("blockNumber").unwrap().as_u64().unwrap() == block_number
+            })
+            .await
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    try_stream! {
+        let mut last_block = None;
+        let mut headers = headers.enumerate();
+        while let Some((header_index, header)) = headers.next().await {
+            let mut header = header?;
+            let mut block = JsonMap::new();
+            let number = header
+                .get("number")
+                .context("Found block without number")?
+                .as_u64()
+                .unwrap();
+            let mut include_block = header
+                .remove(SELECTED_COLUMN)
+                .and_then(|selected| selected.as_bool())
+                .unwrap_or(false);
+
+            block.insert("header".to_owned(), serde_json::to_value(&header)?);
+
+            let mut transactions = block_rows(&mut transactions, number).await?;
+            for tx in transactions.iter_mut() {
+                tx.retain(|key, _| key != "blockNumber");
+                add_nulls(tx, query.fields.as_ref().map(|f| &f.transaction));
+            }
+            if !transactions.is_empty() {
+                include_block = true;
+            }
+            if !transactions.is_empty() || include_tx {
+                block.insert(
+                    "transactions".to_owned(),
+                    serde_json::to_value(transactions)?,
+                );
+            }
+
+            let mut logs = block_rows(&mut logs, number).await?;
+            for log in logs.iter_mut() {
+                log.retain(|key, _| key != "blockNumber");
+                add_nulls(log, query.fields.as_ref().map(|f| &f.log));
+            }
+            if !logs.is_empty() {
+                include_block = true;
+            }
+            if !logs.is_empty() || include_logs {
+                block.insert(
+                    "logs".to_owned(),
+                    serde_json::to_value(logs)?,
+                );
+            }
+
+            if include_block || query.include_all_blocks || header_index == 0 {
+                yield Value::Object(block);
+                last_block = None;
+            } else {
+                last_block = Some(block);
+            }
+        }
+        if let Some(block) = last_block {
+            yield Value::Object(block);
+        }
+    }
+}
+
+async fn consume_while<T, E>(
+    mut stream: Pin<&mut Peekable<impl Stream<Item = Result<T, E>>>>,
+    f: impl Fn(&T) -> bool,
+) -> Result<Vec<T>, E> {
+    let mut result = Vec::new();
+    while let Some(item) = stream
+        .as_mut()
+        .next_if(|item| match item {
+            Err(_) => true,
+            Ok(item) => f(item),
+        })
+        .await
+    {
+        match item {
+            Ok(item) => {
+                result.push(item);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(result)
+}
+
+async fn collect_result(
+    stream: impl Stream<Item = anyhow::Result<Value>>,
+) -> anyhow::Result<Vec<Value>> {
+    let mut result = Vec::new();
+    tokio::pin!(stream);
+    while let Some(row) = stream.next().await {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+fn add_nulls(value: &mut JsonMap<String, Value>, fields: Option<&Vec<String>>) {
+    if let Some(fields) = fields {
+        for field in fields {
+            value.entry(field).or_insert(Value::Null);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use camino::Utf8PathBuf as PathBuf;
 use criterion::{criterion_group, criterion_main, Criterion};
+use datafusion::physical_plan::{memory::MemoryStream, SendableRecordBatchStream};
 use datafusion::prelude::SessionContext;
 use tokio::runtime::Runtime;
 
@@ -9,6 +10,8 @@ use subsquid_worker::query;
 #[cfg(test)]
 use subsquid_worker::util::tests::tests_data;
 
+mod legacy;
+
 async fn prepare_context() -> Result<SessionContext> {
     let root = tests_data().join("0017881390/0017881390-0017882786-32ee9457");
     query::context::prepare_query_context(&root).await
@@ -45,9 +48,55 @@ pub fn query_processing(c: &mut Criterion) {
     }
 }
 
+/// Compares writing the JSON response straight from the batches with
+/// the previous implementation building `serde_json` values from the same batches
+pub fn response_encoding(c: &mut Criterion) {
+    let ctx = futures::executor::block_on(prepare_context()).unwrap();
+    let query = get_query("load_all_tx_and_logs").unwrap();
+    let runtime = Runtime::new().unwrap();
+    let output = runtime
+        .block_on(query::processor::process_query_tables(&ctx, query.clone()))
+        .unwrap();
+    let stream = |name: &str| -> SendableRecordBatchStream {
+        let table = output
+            .tables
+            .iter()
+            .find(|table| table.name == name)
+            .unwrap();
+        Box::pin(MemoryStream::try_new(table.batches.clone(), table.schema.clone(), None).unwrap())
+    };
+
+    let mut group = c.benchmark_group("load_all_tx_and_logs_encoding");
+    group.bench_function("write_response", |b| {
+        b.to_async(&runtime).iter(|| async {
+            query::processor::write_response(
+                &query,
+                stream("blocks"),
+                Some(stream("transactions")),
+                Some(stream("logs")),
+            )
+            .await
+            .unwrap()
+        })
+    });
+    group.bench_function("legacy_build_response", |b| {
+        b.to_async(&runtime).iter(|| async {
+            legacy::build_response(
+                &query,
+                stream("blocks"),
+                Some(stream("transactions")),
+                Some(stream("logs")),
+            )
+            .await
+            .unwrap()
+        })
+    });
+    group.finish();
+}
+
 criterion_group!(
     name = benches;
     config = Criterion::default().sample_size(10);
-    targets = query_processing
+    targets = query_processing, response_encoding
 );
 criterion_main!(benches);
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use axum::{
+    http::{HeaderMap, Method, StatusCode},
+    response::IntoResponse,
+};
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use sha2::Sha256;
+use subsquid_network_transport::PeerId;
+
+use crate::config::Config;
+
+pub const CLIENT_ID_HEADER: &str = "x-client-id";
+pub const TIMESTAMP_HEADER: &str = "x-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-signature";
+pub const NONCE_HEADER: &str = "x-nonce";
+
+/// Signed requests are rejected if their timestamp differs from the current time more than this
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+/// Limits the memory used for remembering the nonces, e.g. a UUID fits
+const MAX_NONCE_LEN: usize = 64;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum AuthError {
+    #[error("Authentication required")]
+    Missing,
+    #[error("Invalid credentials: {0}")]
+    Invalid(&'static str),
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> axum::response::Response {
+        (StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+    }
+}
+
+struct Client {
+    id: PeerId,
+    token: Option<String>,
+    hmac_secret: Option<Vec<u8>>,
+}
+
+/// Maps the HTTP API callers to client identities.
+///
+/// Clients authenticate either with an `Authorization: Bearer <token>` header or by signing
+/// the request: `X-Client-Id` is the client's peer ID, `X-Timestamp` is the current unix time
+/// in seconds, `X-Nonce` is a unique request ID (at most 64 characters) and `X-Signature` is
+/// the hex-encoded HMAC-SHA256 of `"{method}\n{path}\n{timestamp}\n{nonce}\n"` followed by
+/// the request body.
+/// Each nonce is accepted only once per client, so a captured request can't be replayed.
+/// Identical requests, including retries, have to be sent with different nonces.
+///
+/// The identity is used as the client's key in the queries queue and the rate limiter.
+/// CU allocations are only checked in P2P mode, where the identity is treated as a gateway
+/// peer ID, so HTTP clients without an allocation are rejected there.
+#[derive(Default)]
+pub struct Authenticator {
+    clients: Vec<Client>,
+    required: bool,
+    metrics_token: Option<String>,
+    admin_token: Option<String>,
+    // Accepted nonces with their timestamps. Older ones are rejected by the clock skew check.
+    seen_nonces: Mutex<HashMap<(PeerId, String), u64>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &Config) -> Result<Self> {
+        let clients = config
+            .auth
+            .clients
+            .iter()
+            .map(|client| {
+                Ok(Client {
+                    id: client
+                        .id
+                        .parse()
+                        .with_context(|| format!("Invalid client ID: {}", client.id))?,
+                    token: client.token.clone(),
+                    hmac_secret: client.hmac_secret.clone().map(String::into_bytes),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            clients,
+            required: config.auth.required,
+            metrics_token: config.auth.metrics_token.clone(),
+            admin_token: config.admin.token.clone(),
+            seen_nonces: Default::default(),
+        })
+    }
+
+    /// Returns the identity of the client or `None` for anonymous requests
+    pub fn authenticate(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Option<PeerId>, AuthError> {
+        if let Some(token) = bearer_token(headers) {
+            return self
+                .clients
+                .iter()
+                .find(|client| {
+                    client
+                        .token
+                        .as_ref()
+                        .is_some_and(|expected| constant_time_eq(token, expected))
+                })
+                .map(|client| Some(client.id))
+                .ok_or(AuthError::Invalid("unknown token"));
+        }
+        if headers.contains_key(SIGNATURE_HEADER) {
+            return self
+                .verify_signature(method, path, headers, body, SystemTime::now())
+                .map(Some);
+        }
+        if self.required {
+            Err(AuthError::Missing)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn metrics_token(&self) -> Option<&str> {
+        self.metrics_token.as_deref()
+    }
+
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+
+    fn verify_signature(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+        now: SystemTime,
+    ) -> Result<PeerId, AuthError> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(AuthError::Invalid("missing signature headers"))
+        };
+        let client_id: PeerId = header(CLIENT_ID_HEADER)?
+            .parse()
+            .map_err(|_| AuthError::Invalid("malformed client ID"))?;
+        let timestamp: u64 = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| AuthError::Invalid("malformed timestamp"))?;
+        let nonce = header(NONCE_HEADER)?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(AuthError::Invalid("malformed nonce"));
+        }
+        let signature = hex::decode(header(SIGNATURE_HEADER)?)
+            .map_err(|_| AuthError::Invalid("malformed signature"))?;
+
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now.abs_diff(timestamp) > MAX_CLOCK_SKEW.as_secs() {
+            return Err(AuthError::Invalid("request timestamp is too far from now"));
+        }
+
+        let secret = self
+            .clients
+            .iter()
+            .find(|client| client.id == client_id)
+            .and_then(|client| client.hmac_secret.as_ref())
+            .ok_or(AuthError::Invalid("unknown client"))?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+        mac.update(signed_prefix(method, path, timestamp, nonce).as_bytes());
+        mac.update(body);
+        mac.verify_slice(&signature)
+            .map_err(|_| AuthError::Invalid("signature mismatch"))?;
+
+        let mut seen = self.seen_nonces.lock();
+        seen.retain(|_, seen_at| now.abs_diff(*seen_at) <= MAX_CLOCK_SKEW.as_secs());
+        if seen
+            .insert((client_id, nonce.to_owned()), timestamp)
+            .is_some()
+        {
+            return Err(AuthError::Invalid("nonce has already been used"));
+        }
+        Ok(client_id)
+    }
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn signed_prefix(method: &Method, path: &str, timestamp: u64, nonce: &str) -> String {
+    format!("{method}\n{path}\n{timestamp}\n{nonce}\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use axum::http::{HeaderMap, Method};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use subsquid_network_transport::PeerId;
+
+    use super::{signed_prefix, AuthError, Authenticator};
+    use crate::config::{ClientConfig, Config};
+
+    fn authenticator(required: bool) -> (Authenticator, PeerId, PeerId) {
+        let token_client = PeerId::random();
+        let hmac_client = PeerId::random();
+        let mut config = Config::default();
+        config.auth.required = required;
+        config.auth.clients = vec![
+            ClientConfig {
+                id: token_client.to_string(),
+                token: Some("secret-token".to_owned()),
+                hmac_secret: None,
+            },
+            ClientConfig {
+                id: hmac_client.to_string(),
+                token: None,
+                hmac_secret: Some("hmac-secret".to_owned()),
+            },
+        ];
+        (
+            Authenticator::new(&config).unwrap(),
+            token_client,
+            hmac_client,
+        )
+    }
+
+    fn signed_headers(
+        client: PeerId,
+        secret: &str,
+        timestamp: u64,
+        nonce: &str,
+        body: &[u8],
+    ) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(signed_prefix(&Method::POST, "/query/ds", timestamp, nonce).as_bytes());
+        mac.update(body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-client-id", client.to_string().parse().unwrap());
+        headers.insert("x-timestamp", timestamp.to_string().parse().unwrap());
+        headers.insert("x-nonce", nonce.parse().unwrap());
+        headers.insert("x-signature", signature.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let (auth, client, _) = authenticator(false);
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            auth.authenticate(&Method::POST, "/query/ds", &headers, b""),
+            Ok(None)
+        );
+
+        headers.insert("authorization", "Bearer secret-token".parse().unwrap());
+        assert_eq!(
+            auth.authenticate(&Method::POST, "/query/ds", &headers, b""),
+            Ok(Some(client))
+        );
+
+        headers.insert("authorization", "Bearer wrong-token".parse().unwrap());
+        assert!(matches!(
+            auth.authenticate(&Method::POST, "/query/ds", &headers, b""),
+            Err(AuthError::Invalid(_))
+        ));
+
+        let (auth, _, _) = authenticator(true);
+        assert_eq!(
+            auth.authenticate(&Method::POST, "/query/ds", &HeaderMap::new(), b""),
+            Err(AuthError::Missing)
+        );
+    }
+
+    #[test]
+    fn test_signature() {
+        let (auth, _, client) = authenticator(true);
+        let now = SystemTime::now();
+        let timestamp = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let body = b"{\"fromBlock\": 0}";
+
+        let headers = signed_headers(client, "hmac-secret", timestamp, "1", body);
+        assert_eq!(
+            auth.verify_signature(&Method::POST, "/query/ds", &headers, body, now),
+            Ok(client)
+        );
+        assert!(auth
+            .verify_signature(&Method::POST, "/query/ds", &headers, b"{}", now)
+            .is_err());
+        assert!(auth
+            .verify_signature(
+                &Method::POST,
+                "/query/ds",
+                &headers,
+                body,
+                now + Duration::from_secs(600)
+            )
+            .is_err());
+
+        let headers = signed_headers(client, "wrong-secret", timestamp, "2", body);
+        assert!(auth
+            .verify_signature(&Method::POST, "/query/ds", &headers, body, now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_replayed_signature() {
+        let (auth, _, client) = authenticator(true);
+        let now = SystemTime::now();
+        let timestamp = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let body = b"{\"fromBlock\": 0}";
+
+        let headers = signed_headers(client, "hmac-secret", timestamp, "1", body);
+        assert_eq!(
+            auth.verify_signature(&Method::POST, "/query/ds", &headers, body, now),
+            Ok(client)
+        );
+        assert_eq!(
+            auth.verify_signature(&Method::POST, "/query/ds", &headers, body, now),
+            Err(AuthError::Invalid("nonce has already been used"))
+        );
+
+        // A retry of the same request within the same second uses a new nonce
+        let headers = signed_headers(client, "hmac-secret", timestamp, "2", body);
+        assert_eq!(
+            auth.verify_signature(&Method::POST, "/query/ds", &headers, body, now),
+            Ok(client)
+        );
+
+        // The nonce can't be swapped without re-signing the request
+        let mut headers = signed_headers(client, "hmac-secret", timestamp, "3", body);
+        headers.insert("x-nonce", "4".parse().unwrap());
+        assert_eq!(
+            auth.verify_signature(&Method::POST, "/query/ds", &headers, body, now),
+            Err(AuthError::Invalid("signature mismatch"))
+        );
+    }
+}
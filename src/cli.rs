@@ -1,10 +1,10 @@
-use std::time::Duration;
-
-use anyhow::Result;
+use anyhow::{Context, Result};
 use camino::Utf8PathBuf as PathBuf;
 use clap::Parser;
 use subsquid_network_transport::{PeerId, TransportArgs};
 
+use crate::config::Config;
+
 #[derive(Parser)]
 #[command(version)]
 pub struct Args {
@@ -22,20 +22,146 @@ pub struct Args {
     #[clap(short, long, env, default_value_t = 8000)]
     pub port: u16,
 
+    #[command(flatten)]
+    pub config: ConfigArgs,
+
     #[command(subcommand)]
     pub mode: Mode,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ConfigArgs {
+    /// Path to the TOML file with the worker settings
+    #[clap(long = "config", env = "CONFIG_PATH", value_name = "FILE")]
+    pub config_path: Option<PathBuf>,
+
+    // Settings below take precedence over the config file
+    #[clap(long, env, hide(true))]
+    pub parallel_queries: Option<usize>,
+
+    #[clap(long, env, hide(true))]
+    pub queued_queries: Option<usize>,
+
+    #[clap(long, env, hide(true))]
+    pub total_queued_queries: Option<usize>,
+
+    #[clap(long, env, hide(true))]
+    pub client_rate_limit: Option<f64>,
+
+    #[clap(long, env, hide(true))]
+    pub query_timeout_sec: Option<u64>,
+
+    #[clap(long, env, hide(true))]
+    pub sql_enabled: Option<bool>,
+
+    #[clap(long, env, hide(true))]
+    pub concurrent_downloads: Option<usize>,
+
+    #[clap(long, env = "S3_TIMEOUT", hide(true))]
+    pub s3_timeout_sec: Option<u64>,
+
+    #[clap(long, env = "S3_READ_TIMEOUT", hide(true))]
+    pub s3_read_timeout_sec: Option<u64>,
+
+    #[clap(long, env, hide(true))]
+    pub ping_interval_sec: Option<u64>,
 
-    #[clap(env, hide(true), default_value_t = 3)]
-    pub concurrent_downloads: usize,
+    #[clap(long, env, hide(true))]
+    pub network_polling_interval_sec: Option<u64>,
 
-    #[clap(env = "PING_INTERVAL_SEC", hide(true), value_parser=parse_seconds, default_value = "10")]
-    pub ping_interval: Duration,
+    #[clap(long, env, hide(true))]
+    pub logs_send_interval_sec: Option<u64>,
 
-    #[clap(env, hide(true))]
+    #[clap(long, env, hide(true))]
+    pub exit_on_unsupported_version: Option<bool>,
+
+    #[command(flatten)]
+    pub cost_model: CostModelArgs,
+
+    #[clap(long, env, hide(true))]
     pub sentry_dsn: Option<String>,
 
-    #[clap(env, hide(true), default_value_t = 0.001)]
-    pub sentry_traces_sample_rate: f32,
+    #[clap(long, env, hide(true))]
+    pub sentry_traces_sample_rate: Option<f32>,
+
+    #[clap(long, env, hide(true))]
+    pub admin_token: Option<String>,
+}
+
+impl ConfigArgs {
+    /// Merges the config file with the values from the command line and environment.
+    /// The file is re-read on every call.
+    pub fn load(&self) -> Result<Config> {
+        let mut config = match &self.config_path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        self.clone().apply(&mut config);
+        config.validate().context("Invalid configuration")?;
+        Ok(config)
+    }
+
+    fn apply(self, config: &mut Config) {
+        fn set<T>(dst: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *dst = value;
+            }
+        }
+        let queries = &mut config.queries;
+        set(&mut queries.parallel_queries, self.parallel_queries);
+        set(&mut queries.queued_queries, self.queued_queries);
+        set(&mut queries.total_queued_queries, self.total_queued_queries);
+        if self.client_rate_limit.is_some() {
+            queries.client_rate_limit = self.client_rate_limit;
+        }
+        set(&mut queries.timeout_sec, self.query_timeout_sec);
+        set(&mut queries.sql_enabled, self.sql_enabled);
+        let downloads = &mut config.downloads;
+        set(
+            &mut downloads.concurrent_downloads,
+            self.concurrent_downloads,
+        );
+        set(&mut downloads.s3_timeout_sec, self.s3_timeout_sec);
+        set(&mut downloads.s3_read_timeout_sec, self.s3_read_timeout_sec);
+        let network = &mut config.network;
+        set(&mut network.ping_interval_sec, self.ping_interval_sec);
+        set(
+            &mut network.network_polling_interval_sec,
+            self.network_polling_interval_sec,
+        );
+        set(
+            &mut network.logs_send_interval_sec,
+            self.logs_send_interval_sec,
+        );
+        set(
+            &mut network.exit_on_unsupported_version,
+            self.exit_on_unsupported_version,
+        );
+        let cost_model = &mut config.cost_model;
+        set(&mut cost_model.base_cost, self.cost_model.cu_base_cost);
+        set(&mut cost_model.per_chunk, self.cost_model.cu_per_chunk);
+        set(
+            &mut cost_model.per_scanned_mb,
+            self.cost_model.cu_per_scanned_mb,
+        );
+        set(
+            &mut cost_model.per_output_mb,
+            self.cost_model.cu_per_output_mb,
+        );
+        if self.cost_model.cu_max_estimate.is_some() {
+            cost_model.max_estimate = self.cost_model.cu_max_estimate;
+        }
+        if self.sentry_dsn.is_some() {
+            config.sentry.dsn = self.sentry_dsn;
+        }
+        set(
+            &mut config.sentry.traces_sample_rate,
+            self.sentry_traces_sample_rate,
+        );
+        if self.admin_token.is_some() {
+            config.admin.token = self.admin_token;
+        }
+    }
 }
 
 #[derive(clap::Args, Debug, Clone)]
@@ -63,20 +189,45 @@ pub struct P2PArgs {
     #[clap(long, env)]
     pub logs_collector_id: PeerId,
 
-    #[clap(env = "NETWORK_POLLING_INTERVAL_SEC", hide(true), value_parser=parse_seconds, default_value = "30")]
-    pub network_polling_interval: Duration,
-
     #[command(flatten)]
     pub transport: TransportArgs,
 }
 
+/// Compute units charged for each query
+#[derive(clap::Args, Debug, Clone)]
+pub struct CostModelArgs {
+    #[clap(long, env, hide(true))]
+    pub cu_base_cost: Option<u64>,
+
+    #[clap(long, env, hide(true))]
+    pub cu_per_chunk: Option<u64>,
+
+    #[clap(long, env, hide(true))]
+    pub cu_per_scanned_mb: Option<u64>,
+
+    #[clap(long, env, hide(true))]
+    pub cu_per_output_mb: Option<u64>,
+
+    #[clap(long, env, hide(true))]
+    pub cu_max_estimate: Option<u64>,
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(clap::Subcommand)]
 pub enum Mode {
     Http(HttpArgs),
     P2P(P2PArgs),
+    /// Inspect the worker configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
 }
 
-fn parse_seconds(s: &str) -> Result<Duration> {
-    Ok(Duration::from_secs(s.parse()?))
+#[derive(clap::Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration merged from all sources
+    Print {
+        /// Print tokens, secrets and the Sentry DSN instead of masking them
+        #[clap(long)]
+        show_secrets: bool,
+    },
 }
@@ -0,0 +1,448 @@
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use camino::Utf8Path as Path;
+use serde::{Deserialize, Serialize};
+use subsquid_network_transport::PeerId;
+use tracing_subscriber::EnvFilter;
+
+use crate::gateway_allocations::cost_model::CostModel;
+
+/// Printed instead of the secrets, see [`Config::redacted`]
+const REDACTED: &str = "***";
+
+/// Tunable settings of the worker.
+/// Values are taken from (highest priority first) command line arguments,
+/// environment variables, the config file and the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Logs filter in the `RUST_LOG` format. Overrides the `RUST_LOG` variable if set.
+    pub log_level: Option<String>,
+    pub queries: QueriesConfig,
+    pub downloads: DownloadsConfig,
+    pub network: NetworkConfig,
+    pub cost_model: CostModel,
+    pub sentry: SentryConfig,
+    pub admin: AdminConfig,
+    pub auth: AuthConfig,
+    pub result_cache: ResultCacheConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueriesConfig {
+    pub parallel_queries: usize,
+    /// Max number of queued queries for each client
+    pub queued_queries: usize,
+    /// Max number of queued queries of all clients together
+    pub total_queued_queries: usize,
+    /// Max number of queries per second from a single client
+    pub client_rate_limit: Option<f64>,
+    /// Max time a query may spend in the queue and executing. HTTP clients can only request less,
+    /// P2P queries always use this timeout.
+    pub timeout_sec: u64,
+    /// Enables the read-only `/sql/:dataset` HTTP endpoint
+    pub sql_enabled: bool,
+    /// Max number of rows returned by an SQL query
+    pub sql_max_rows: usize,
+    /// Threads of the query execution runtime. Defaults to the number of CPUs.
+    pub threads: Option<usize>,
+    /// Memory available to all running queries. Unlimited if not set.
+    pub memory_limit_mb: Option<u64>,
+    /// Memory available to a single query. Unlimited if not set.
+    /// Sorts spill to disk when they reach it, other operators fail the query.
+    pub query_memory_limit_mb: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadsConfig {
+    pub concurrent_downloads: usize,
+    /// Timeout for downloading a single file
+    pub s3_timeout_sec: u64,
+    pub s3_read_timeout_sec: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub ping_interval_sec: u64,
+    pub network_polling_interval_sec: u64,
+    pub logs_send_interval_sec: u64,
+    /// Shut down when the scheduler reports that this version is no longer supported
+    pub exit_on_unsupported_version: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SentryConfig {
+    pub dsn: Option<String>,
+    pub traces_sample_rate: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token for the `/admin` HTTP API. The API is disabled if not set.
+    pub token: Option<String>,
+}
+
+/// Authentication of the HTTP API clients
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Reject queries without credentials. Otherwise they are executed as anonymous.
+    pub required: bool,
+    /// Bearer token for the `/metrics` endpoint. The endpoint is open if not set.
+    pub metrics_token: Option<String>,
+    pub clients: Vec<ClientConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Peer ID the client's queries are attributed to, e.g. when spending compute units
+    pub id: String,
+    /// Secret for the `Authorization: Bearer` header
+    pub token: Option<String>,
+    /// Secret for signing requests with HMAC-SHA256
+    pub hmac_secret: Option<String>,
+}
+
+/// Cache of successful query results. Each tier is disabled if its size is 0.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResultCacheConfig {
+    pub memory_mb: u64,
+    pub disk_mb: u64,
+}
+
+impl Default for QueriesConfig {
+    fn default() -> Self {
+        Self {
+            parallel_queries: 3,
+            queued_queries: 15,
+            total_queued_queries: 200,
+            client_rate_limit: None,
+            timeout_sec: 60,
+            sql_enabled: false,
+            sql_max_rows: 10_000,
+            threads: None,
+            memory_limit_mb: None,
+            query_memory_limit_mb: None,
+        }
+    }
+}
+
+impl Default for DownloadsConfig {
+    fn default() -> Self {
+        Self {
+            concurrent_downloads: 3,
+            s3_timeout_sec: 60,
+            s3_read_timeout_sec: 3,
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval_sec: 10,
+            network_polling_interval_sec: 30,
+            logs_send_interval_sec: 600,
+            exit_on_unsupported_version: false,
+        }
+    }
+}
+
+impl Default for SentryConfig {
+    fn default() -> Self {
+        Self {
+            dsn: None,
+            traces_sample_rate: 0.001,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read config file {path}"))?;
+        Self::parse(&contents).with_context(|| format!("Invalid config file {path}"))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("Config should be serializable")
+    }
+
+    /// Masks the tokens, secrets and the Sentry DSN, so that the config can be printed
+    pub fn redacted(&self) -> Self {
+        fn redact(value: &mut Option<String>) {
+            if value.is_some() {
+                *value = Some(REDACTED.to_owned());
+            }
+        }
+        let mut config = self.clone();
+        redact(&mut config.admin.token);
+        redact(&mut config.auth.metrics_token);
+        for client in config.auth.clients.iter_mut() {
+            redact(&mut client.token);
+            redact(&mut client.hmac_secret);
+        }
+        redact(&mut config.sentry.dsn);
+        config
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(log_level) = &self.log_level {
+            EnvFilter::try_new(log_level)
+                .with_context(|| format!("log_level is invalid: {log_level}"))?;
+        }
+        ensure!(
+            self.queries.parallel_queries > 0,
+            "queries.parallel_queries should be positive"
+        );
+        ensure!(
+            self.queries.queued_queries > 0,
+            "queries.queued_queries should be positive"
+        );
+        ensure!(
+            self.queries.total_queued_queries > 0,
+            "queries.total_queued_queries should be positive"
+        );
+        if let Some(rate) = self.queries.client_rate_limit {
+            ensure!(
+                rate.is_finite() && rate > 0.0,
+                "queries.client_rate_limit should be a positive number, got {rate}"
+            );
+        }
+        ensure!(
+            self.queries.timeout_sec > 0,
+            "queries.timeout_sec should be positive"
+        );
+        ensure!(
+            self.queries.sql_max_rows > 0,
+            "queries.sql_max_rows should be positive"
+        );
+        ensure!(
+            self.queries.threads != Some(0),
+            "queries.threads should be positive"
+        );
+        ensure!(
+            self.queries.memory_limit_mb != Some(0)
+                && self.queries.query_memory_limit_mb != Some(0),
+            "queries memory limits should be positive"
+        );
+        ensure!(
+            self.downloads.concurrent_downloads > 0,
+            "downloads.concurrent_downloads should be positive"
+        );
+        ensure!(
+            self.downloads.s3_timeout_sec > 0 && self.downloads.s3_read_timeout_sec > 0,
+            "downloads timeouts should be positive"
+        );
+        ensure!(
+            self.network.ping_interval_sec > 0
+                && self.network.network_polling_interval_sec > 0
+                && self.network.logs_send_interval_sec > 0,
+            "network intervals should be positive"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&self.sentry.traces_sample_rate),
+            "sentry.traces_sample_rate should be between 0 and 1, got {}",
+            self.sentry.traces_sample_rate
+        );
+        if let Some(token) = &self.admin.token {
+            ensure!(!token.is_empty(), "admin.token shouldn't be empty");
+        }
+        if let Some(token) = &self.auth.metrics_token {
+            ensure!(!token.is_empty(), "auth.metrics_token shouldn't be empty");
+        }
+        for client in &self.auth.clients {
+            client
+                .id
+                .parse::<PeerId>()
+                .with_context(|| format!("auth.clients: invalid peer ID {}", client.id))?;
+            ensure!(
+                client.token.is_some() || client.hmac_secret.is_some(),
+                "auth.clients: client {} has neither token nor hmac_secret",
+                client.id
+            );
+            ensure!(
+                client.token.as_ref().map_or(true, |t| !t.is_empty())
+                    && client.hmac_secret.as_ref().map_or(true, |s| !s.is_empty()),
+                "auth.clients: client {} has an empty secret",
+                client.id
+            );
+        }
+        Ok(())
+    }
+
+    /// Names of the changed settings that only take effect after a restart
+    pub fn non_reloadable_changes(&self, new: &Config) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.queries.client_rate_limit != new.queries.client_rate_limit {
+            changes.push("queries.client_rate_limit");
+        }
+        if self.queries.sql_enabled != new.queries.sql_enabled {
+            changes.push("queries.sql_enabled");
+        }
+        if self.queries.threads != new.queries.threads {
+            changes.push("queries.threads");
+        }
+        if self.queries.memory_limit_mb != new.queries.memory_limit_mb {
+            changes.push("queries.memory_limit_mb");
+        }
+        if self.queries.query_memory_limit_mb != new.queries.query_memory_limit_mb {
+            changes.push("queries.query_memory_limit_mb");
+        }
+        if self.network != new.network {
+            changes.push("network");
+        }
+        if self.cost_model != new.cost_model {
+            changes.push("cost_model");
+        }
+        if self.sentry != new.sentry {
+            changes.push("sentry");
+        }
+        if self.admin != new.admin {
+            changes.push("admin");
+        }
+        if self.auth != new.auth {
+            changes.push("auth");
+        }
+        if self.result_cache != new.result_cache {
+            changes.push("result_cache");
+        }
+        changes
+    }
+
+    pub fn logs_filter(&self) -> EnvFilter {
+        match &self.log_level {
+            Some(log_level) => EnvFilter::new(log_level),
+            None => EnvFilter::from_default_env(),
+        }
+    }
+}
+
+impl QueriesConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_sec)
+    }
+}
+
+impl DownloadsConfig {
+    pub fn s3_timeout(&self) -> Duration {
+        Duration::from_secs(self.s3_timeout_sec)
+    }
+
+    pub fn s3_read_timeout(&self) -> Duration {
+        Duration::from_secs(self.s3_read_timeout_sec)
+    }
+}
+
+impl ResultCacheConfig {
+    pub fn enabled(&self) -> bool {
+        self.memory_mb > 0 || self.disk_mb > 0
+    }
+}
+
+impl NetworkConfig {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_sec)
+    }
+
+    pub fn network_polling_interval(&self) -> Duration {
+        Duration::from_secs(self.network_polling_interval_sec)
+    }
+
+    pub fn logs_send_interval(&self) -> Duration {
+        Duration::from_secs(self.logs_send_interval_sec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientConfig, Config};
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            [queries]
+            parallel_queries = 8
+            client_rate_limit = 2.5
+
+            [cost_model]
+            per_chunk = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.queries.parallel_queries, 8);
+        assert_eq!(config.queries.queued_queries, 15);
+        assert_eq!(config.queries.total_queued_queries, 200);
+        assert_eq!(config.queries.client_rate_limit, Some(2.5));
+        assert_eq!(config.cost_model.base_cost, 1);
+        assert_eq!(config.cost_model.per_chunk, 2);
+        assert_eq!(config.downloads, Default::default());
+        config.validate().unwrap();
+
+        assert!(Config::parse("[queries]\nparalel_queries = 8").is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let config = Config::default();
+        assert_eq!(Config::parse(&config.to_toml()).unwrap(), config);
+    }
+
+    #[test]
+    fn test_redacted() {
+        let mut config = Config::default();
+        config.admin.token = Some("admin-token".to_owned());
+        config.auth.clients.push(ClientConfig {
+            hmac_secret: Some("hmac-secret".to_owned()),
+            ..Default::default()
+        });
+        config.sentry.dsn = Some("https://key@sentry.example/1".to_owned());
+        let printed = config.redacted().to_toml();
+        for secret in ["admin-token", "hmac-secret", "key@sentry"] {
+            assert!(!printed.contains(secret), "{secret} is printed");
+        }
+        assert!(printed.contains(super::REDACTED));
+        assert_eq!(config.redacted().auth.metrics_token, None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        config.queries.parallel_queries = 0;
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("queries.parallel_queries"));
+
+        let config = Config {
+            log_level: Some("worker=verbose".to_owned()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_non_reloadable_changes() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.queries.parallel_queries = 10;
+        new.downloads.concurrent_downloads = 10;
+        new.log_level = Some("debug".to_owned());
+        assert!(old.non_reloadable_changes(&new).is_empty());
+
+        new.network.ping_interval_sec = 1;
+        new.cost_model.base_cost = 2;
+        assert_eq!(old.non_reloadable_changes(&new), ["network", "cost_model"]);
+    }
+}
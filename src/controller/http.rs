@@ -7,7 +7,7 @@ use crate::{
     gateway_allocations::allocations_checker::NoopAllocationsChecker, storage::manager::Status,
 };
 
-use super::worker::Worker;
+use super::{lifecycle::LifecycleState, worker::Worker};
 
 const PING_TIMEOUT: Duration = Duration::from_millis(200);
 
@@ -37,6 +37,8 @@ impl HttpController {
     }
 
     pub async fn run(&self, cancellation_token: CancellationToken) {
+        // There is no registration in the HTTP mode
+        self.worker.lifecycle().transition(LifecycleState::Active);
         let mut timer = tokio::time::interval_at(
             tokio::time::Instant::now() + self.ping_interval,
             self.ping_interval,
@@ -57,6 +59,7 @@ impl HttpController {
             }
             // TODO: receive assignment
         }
+        self.worker.lifecycle().transition(LifecycleState::Stopping);
     }
 
     async fn send_ping(&self, status: Status) -> anyhow::Result<()> {
@@ -0,0 +1,120 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tracing::info;
+
+use crate::metrics::{self, WorkerStatus};
+
+/// State of the worker in the network. Queries are only accepted in the `Active` state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LifecycleState {
+    /// Waiting for the first response from the scheduler
+    Starting,
+    Active,
+    /// Jailed until the end of the epoch with the given reason
+    Jailed(String),
+    NotRegistered,
+    UnsupportedVersion,
+    /// The worker is shutting down. There is no way back from this state.
+    Stopping,
+}
+
+impl LifecycleState {
+    pub fn accepts_queries(&self) -> bool {
+        matches!(self, Self::Active)
+    }
+
+    pub fn can_transition_to(&self, next: &LifecycleState) -> bool {
+        self != next && *self != Self::Stopping && *next != Self::Starting
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Starting => "starting",
+            Self::Active => "active",
+            Self::Jailed(_) => "jailed",
+            Self::NotRegistered => "not_registered",
+            Self::UnsupportedVersion => "unsupported_version",
+            Self::Stopping => "stopping",
+        }
+    }
+}
+
+impl std::fmt::Display for LifecycleState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Jailed(reason) => write!(f, "jailed ({reason})"),
+            state => f.write_str(state.as_str()),
+        }
+    }
+}
+
+impl From<&LifecycleState> for WorkerStatus {
+    fn from(state: &LifecycleState) -> Self {
+        match state {
+            LifecycleState::Starting => WorkerStatus::Starting,
+            LifecycleState::Active => WorkerStatus::Active,
+            LifecycleState::Jailed(_) => WorkerStatus::Jailed,
+            LifecycleState::NotRegistered => WorkerStatus::NotRegistered,
+            LifecycleState::UnsupportedVersion => WorkerStatus::UnsupportedVersion,
+            LifecycleState::Stopping => WorkerStatus::Stopping,
+        }
+    }
+}
+
+pub struct Lifecycle {
+    state: Mutex<(LifecycleState, Instant)>,
+}
+
+impl Lifecycle {
+    pub fn new(initial: LifecycleState) -> Self {
+        Self {
+            state: Mutex::new((initial, Instant::now())),
+        }
+    }
+
+    pub fn state(&self) -> LifecycleState {
+        self.state.lock().0.clone()
+    }
+
+    /// Time spent in the current state
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().1.elapsed()
+    }
+
+    /// Returns `false` if the transition is not allowed or the state is unchanged
+    pub fn transition(&self, next: LifecycleState) -> bool {
+        let mut state = self.state.lock();
+        if !state.0.can_transition_to(&next) {
+            return false;
+        }
+        info!("Worker state changed: {} -> {}", state.0, next);
+        metrics::set_status((&next).into());
+        *state = (next, Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lifecycle, LifecycleState};
+
+    #[test]
+    fn test_transitions() {
+        let lifecycle = Lifecycle::new(LifecycleState::Starting);
+        assert!(!lifecycle.state().accepts_queries());
+
+        assert!(lifecycle.transition(LifecycleState::Active));
+        assert!(lifecycle.state().accepts_queries());
+        assert!(!lifecycle.transition(LifecycleState::Active));
+        assert!(!lifecycle.transition(LifecycleState::Starting));
+
+        assert!(lifecycle.transition(LifecycleState::Jailed("reason".to_owned())));
+        assert!(!lifecycle.state().accepts_queries());
+        assert!(lifecycle.transition(LifecycleState::Active));
+
+        assert!(lifecycle.transition(LifecycleState::Stopping));
+        assert!(!lifecycle.transition(LifecycleState::Active));
+        assert_eq!(lifecycle.state(), LifecycleState::Stopping);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod http;
+pub mod lifecycle;
 pub mod p2p;
 pub mod worker;
@@ -1,48 +1,48 @@
-use std::{env, sync::Arc, time::Duration};
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use camino::Utf8PathBuf as PathBuf;
-use futures::{Stream, StreamExt};
-use lazy_static::lazy_static;
+use futures::{FutureExt, Stream, StreamExt};
 use subsquid_messages::{
     query_executed, DatasetRanges, InputAndOutput, Ping, Pong, Query, QueryExecuted, SizeAndHash,
 };
 use subsquid_network_transport::{
     P2PTransportBuilder, PeerId, WorkerConfig, WorkerEvent, WorkerTransportHandle,
 };
-use tokio::{
-    sync::{mpsc, watch},
-    time::MissedTickBehavior,
-};
-use tokio_stream::wrappers::{IntervalStream, ReceiverStream};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info, log, warn};
 
 use crate::{
+    config::NetworkConfig,
     gateway_allocations::allocations_checker::RpcAllocationsChecker,
     logs_storage::LogsStorage,
     metrics,
     query::{error::QueryError, result::QueryResult},
     storage::datasets_index::parse_assignment,
-    util::{hash::sha3_256, UseOnce},
+    util::{hash::sha3_256, supervisor::Supervisor, UseOnce},
 };
 
-use super::worker::Worker;
+use super::{lifecycle::LifecycleState, worker::Worker};
 
 const QUERIES_POOL_SIZE: usize = 16;
 const CONCURRENT_QUERY_MESSAGES: usize = 32;
+// Pings are sent less often while the worker is not registered
+const MAX_PING_BACKOFF: Duration = Duration::from_secs(300);
+// Stable server error message for timed out queries, since the protocol has no timeout result
+const TIMEOUT_MESSAGE: &str = "TIMEOUT: query execution timed out";
 
-lazy_static! {
-    static ref LOGS_SEND_INTERVAL: Duration = Duration::from_secs(
-        env::var("LOGS_SEND_INTERVAL_SEC")
-            .map(|s| s.parse().expect("Invalid LOGS_SEND_INTERVAL_SEC"))
-            .unwrap_or(600)
-    );
-}
+// Readiness conditions
+const LOGS_STORAGE_INITIALIZED: &str = "logs_storage_initialized";
+const ASSIGNMENT_RECEIVED: &str = "assignment_received";
+const NOT_JAILED: &str = "not_jailed";
 
 pub struct P2PController<EventStream> {
     worker: Arc<Worker<RpcAllocationsChecker>>,
     ping_interval: Duration,
+    logs_send_interval: Duration,
+    exit_on_unsupported_version: bool,
     raw_event_stream: UseOnce<EventStream>,
     transport_handle: WorkerTransportHandle,
     logs_storage: LogsStorage,
@@ -58,7 +58,7 @@ pub async fn create_p2p_controller(
     scheduler_id: PeerId,
     logs_collector_id: PeerId,
     data_dir: PathBuf,
-    ping_interval: Duration,
+    config: &NetworkConfig,
 ) -> Result<P2PController<impl Stream<Item = WorkerEvent>>> {
     let worker_id = transport_builder.local_peer_id();
     info!("Local peer ID: {worker_id}");
@@ -71,12 +71,20 @@ pub async fn create_p2p_controller(
     let (queries_tx, queries_rx) = mpsc::channel(QUERIES_POOL_SIZE);
     let (last_collected_log_tx, _) = watch::channel(None);
 
+    let logs_storage = LogsStorage::new(data_dir.join("logs.db").as_str()).await?;
+    let readiness = worker.readiness();
+    readiness.set(LOGS_STORAGE_INITIALIZED, logs_storage.is_initialized());
+    readiness.set(ASSIGNMENT_RECEIVED, false);
+    readiness.set(NOT_JAILED, true);
+
     Ok(P2PController {
         worker,
-        ping_interval,
+        ping_interval: config.ping_interval(),
+        logs_send_interval: config.logs_send_interval(),
+        exit_on_unsupported_version: config.exit_on_unsupported_version,
         raw_event_stream: UseOnce::new(event_stream),
         transport_handle,
-        logs_storage: LogsStorage::new(data_dir.join("logs.db").as_str()).await?,
+        logs_storage,
         worker_id,
         queries_tx,
         queries_rx: UseOnce::new(queries_rx),
@@ -85,15 +93,31 @@ pub async fn create_p2p_controller(
 }
 
 impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
-    pub async fn run(&self, cancellation_token: CancellationToken) {
-        // TODO: cancel all the tasks if one of them finishes
-        tokio::join!(
-            self.run_event_loop(cancellation_token.child_token()),
-            self.run_queries_loop(cancellation_token.child_token()),
-            self.run_ping_loop(cancellation_token.child_token(), self.ping_interval),
-            self.run_logs_loop(cancellation_token.child_token(), *LOGS_SEND_INTERVAL),
-            self.worker.run(cancellation_token.child_token()),
-        );
+    pub async fn run(&self, cancellation_token: CancellationToken) -> Result<()> {
+        Supervisor::new(cancellation_token.clone())
+            .with_task(
+                "event_loop",
+                self.run_event_loop(cancellation_token.child_token(), &cancellation_token)
+                    .map(Ok),
+            )
+            .with_task(
+                "queries_loop",
+                self.run_queries_loop(cancellation_token.child_token())
+                    .map(Ok),
+            )
+            .with_task(
+                "ping_loop",
+                self.run_ping_loop(cancellation_token.child_token(), self.ping_interval)
+                    .map(Ok),
+            )
+            .with_task(
+                "logs_loop",
+                self.run_logs_loop(cancellation_token.child_token(), self.logs_send_interval)
+                    .map(Ok),
+            )
+            .with_task("worker", self.worker.run(cancellation_token.child_token()))
+            .run()
+            .await
     }
 
     async fn run_queries_loop(&self, cancellation_token: CancellationToken) {
@@ -107,34 +131,39 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
     }
 
     async fn run_ping_loop(&self, cancellation_token: CancellationToken, ping_interval: Duration) {
-        let mut timer =
-            tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
-        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
-        IntervalStream::new(timer)
-            .take_until(cancellation_token.cancelled_owned())
-            .for_each(|_| async move {
-                tracing::debug!("Sending ping");
-                let status = self.worker.status();
-                let ping = Ping {
-                    stored_ranges: status
-                        .available
-                        .into_iter()
-                        .map(|(dataset, ranges)| DatasetRanges {
-                            url: dataset,
-                            ranges: ranges.ranges,
-                        })
-                        .collect(),
-                    worker_id: Some(self.worker_id.to_string()),
-                    version: Some(env!("CARGO_PKG_VERSION").to_string()),
-                    stored_bytes: Some(status.stored_bytes),
-                    ..Default::default()
-                };
-                let result = self.transport_handle.send_ping(ping);
-                if let Err(err) = result {
-                    warn!("Couldn't send ping: {:?}", err);
-                }
-            })
-            .await;
+        let mut delay = ping_interval;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = cancellation_token.cancelled() => { break; }
+            }
+            delay = match self.worker.lifecycle().state() {
+                // Registration is checked with exponential backoff
+                LifecycleState::NotRegistered => (delay * 2).min(MAX_PING_BACKOFF),
+                _ => ping_interval,
+            };
+
+            tracing::debug!("Sending ping");
+            let status = self.worker.status();
+            let ping = Ping {
+                stored_ranges: status
+                    .available
+                    .into_iter()
+                    .map(|(dataset, ranges)| DatasetRanges {
+                        url: dataset,
+                        ranges: ranges.ranges,
+                    })
+                    .collect(),
+                worker_id: Some(self.worker_id.to_string()),
+                version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                stored_bytes: Some(status.stored_bytes),
+                ..Default::default()
+            };
+            let result = self.transport_handle.send_ping(ping);
+            if let Err(err) = result {
+                warn!("Couldn't send ping: {:?}", err);
+            }
+        }
     }
 
     async fn run_logs_loop(
@@ -165,6 +194,9 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
                 _ = last_collected_log_rx.changed() => {
                     let last_seq_no = *last_collected_log_rx.borrow_and_update();
                     self.logs_storage.logs_collected(last_seq_no).await;
+                    self.worker
+                        .readiness()
+                        .set(LOGS_STORAGE_INITIALIZED, self.logs_storage.is_initialized());
                 }
                 _ = cancellation_token.cancelled() => {
                     break;
@@ -173,7 +205,12 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
         }
     }
 
-    async fn run_event_loop(&self, cancellation_token: CancellationToken) {
+    /// `shutdown` stops the whole worker, e.g. when its version is no longer supported
+    async fn run_event_loop(
+        &self,
+        cancellation_token: CancellationToken,
+        shutdown: &CancellationToken,
+    ) {
         let event_stream = self
             .raw_event_stream
             .take()
@@ -183,7 +220,7 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
 
         while let Some(ev) = event_stream.next().await {
             match ev {
-                WorkerEvent::Pong(pong) => self.handle_pong(pong),
+                WorkerEvent::Pong(pong) => self.handle_pong(pong, shutdown),
                 WorkerEvent::Query { peer_id, query } => {
                     match self.queries_tx.try_send((peer_id, query)) {
                         Ok(_) => {}
@@ -205,21 +242,31 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
         }
     }
 
-    fn handle_pong(&self, pong: Pong) {
+    fn handle_pong(&self, pong: Pong, shutdown: &CancellationToken) {
         use subsquid_messages::pong::Status;
+        let lifecycle = self.worker.lifecycle();
+        if pong.status.is_some() {
+            self.worker.record_pong();
+        }
         match pong.status {
             Some(Status::NotRegistered(())) => {
                 error!("Worker not registered on chain");
-                metrics::set_status(metrics::WorkerStatus::NotRegistered);
+                lifecycle.transition(LifecycleState::NotRegistered);
             }
             Some(Status::UnsupportedVersion(())) => {
                 error!("Worker version not supported by the scheduler");
-                metrics::set_status(metrics::WorkerStatus::UnsupportedVersion);
+                lifecycle.transition(LifecycleState::UnsupportedVersion);
+                if self.exit_on_unsupported_version {
+                    info!("Shutting down because of the unsupported version");
+                    lifecycle.transition(LifecycleState::Stopping);
+                    shutdown.cancel();
+                }
             }
             Some(Status::Jailed(reason)) => {
                 warn!("Worker jailed until the end of epoch: {reason}");
                 self.worker.stop_downloads();
-                metrics::set_status(metrics::WorkerStatus::Jailed);
+                self.worker.readiness().set(NOT_JAILED, false);
+                lifecycle.transition(LifecycleState::Jailed(reason));
             }
             Some(Status::Active(assignment)) => {
                 info!("Received pong from the scheduler");
@@ -227,10 +274,12 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
                     Ok((chunks, datasets_index)) => {
                         self.worker.set_datasets_index(datasets_index);
                         self.worker.set_desired_chunks(chunks);
+                        self.worker.readiness().set(ASSIGNMENT_RECEIVED, true);
                     }
                     Err(e) => warn!("Invalid assignment: {e:?}"),
                 }
-                metrics::set_status(metrics::WorkerStatus::Active);
+                self.worker.readiness().set(NOT_JAILED, true);
+                lifecycle.transition(LifecycleState::Active);
             }
             None => {
                 warn!("Invalid pong message: no status field");
@@ -252,7 +301,7 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
         }
         metrics::query_executed(&result);
 
-        let log = if let Err(QueryError::NoAllocation) = result {
+        let log = if let Err(QueryError::NoAllocation | QueryError::NotServing(_)) = result {
             None
         } else {
             Some(self.generate_log(&result, query, peer_id))
@@ -276,14 +325,16 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
                 "Some fields are missing in proto message".to_owned(),
             ))?;
         };
-        if let Some(future) =
-            self.worker
-                .schedule_query(query_str.clone(), dataset.clone(), Some(peer_id))
-        {
-            future.await
-        } else {
-            Err(QueryError::ServiceOverloaded)
-        }
+        // The P2P query message has no options, so these queries are always limited
+        // by the configured `queries.timeout_sec` and can't request a shorter deadline
+        self.worker
+            .schedule_query(
+                query_str.clone(),
+                dataset.clone(),
+                Some(peer_id),
+                Default::default(),
+            )?
+            .await
     }
 
     fn send_query_result(
@@ -294,15 +345,20 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
         use subsquid_messages::query_result;
         let query_result = match result {
             Ok(result) => query_result::Result::Ok(subsquid_messages::OkResult {
-                data: result.compressed_data,
-                exec_plan: None,
+                data: result.data.into(),
+                exec_plan: result.exec_plan,
             }),
-            Err(e @ QueryError::NotFound) => query_result::Result::BadRequest(e.to_string()),
+            Err(e @ (QueryError::NotFound | QueryError::MemoryLimitExceeded(_))) => {
+                query_result::Result::BadRequest(e.to_string())
+            }
             Err(QueryError::NoAllocation) => query_result::Result::NoAllocation(()),
             Err(QueryError::BadRequest(e)) => query_result::Result::BadRequest(e),
-            Err(e @ QueryError::ServiceOverloaded) => {
+            Err(e @ (QueryError::ServiceOverloaded | QueryError::NotServing(_))) => {
                 query_result::Result::ServerError(e.to_string())
             }
+            Err(QueryError::Timeout) => {
+                query_result::Result::ServerError(TIMEOUT_MESSAGE.to_owned())
+            }
             Err(QueryError::Other(e)) => query_result::Result::ServerError(e.to_string()),
         };
         let query_result = subsquid_messages::QueryResult {
@@ -340,13 +396,20 @@ impl<EventStream: Stream<Item = WorkerEvent>> P2PController<EventStream> {
                     sha3_256: result.data_sha3_256.clone(),
                 }),
             }),
-            Err(e @ QueryError::NotFound) => query_executed::Result::BadRequest(e.to_string()),
+            Err(e @ (QueryError::NotFound | QueryError::MemoryLimitExceeded(_))) => {
+                query_executed::Result::BadRequest(e.to_string())
+            }
             Err(QueryError::BadRequest(e)) => query_executed::Result::BadRequest(e.clone()),
             Err(e @ QueryError::ServiceOverloaded) => {
                 query_executed::Result::ServerError(e.to_string())
             }
+            Err(QueryError::Timeout) => {
+                query_executed::Result::ServerError(TIMEOUT_MESSAGE.to_owned())
+            }
             Err(QueryError::Other(e)) => query_executed::Result::ServerError(e.to_string()),
-            Err(QueryError::NoAllocation) => panic!("Shouldn't send logs with NoAllocation error"),
+            Err(e @ (QueryError::NoAllocation | QueryError::NotServing(_))) => {
+                panic!("Shouldn't send logs with {e:?} error")
+            }
         };
         let query_hash = sha3_256(
             query
@@ -1,57 +1,143 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use futures::{Future, StreamExt};
-use tokio::sync::{mpsc, oneshot};
-use tokio_stream::wrappers::ReceiverStream;
+use anyhow::Result;
+use futures::{stream::FuturesUnordered, Future, FutureExt, StreamExt};
+use parking_lot::Mutex;
+use tokio::{
+    sync::{oneshot, Notify},
+    time::Instant,
+};
 use tokio_util::sync::CancellationToken;
 
 use subsquid_network_transport::PeerId;
 
 use crate::{
+    config::{Config, QueriesConfig},
     gateway_allocations::{self, allocations_checker::AllocationsChecker},
     metrics,
-    query::{self, error::QueryError, eth::BatchRequest, result::QueryResult},
+    query::{
+        self,
+        cache::{cache_key, ResultCache},
+        compression::Compression,
+        error::QueryError,
+        eth::BatchRequest,
+        executor::QueryExecutor,
+        format::ResultFormat,
+        result::{Encoding, QueryResult},
+        sql::SqlRequest,
+    },
     storage::{
         datasets_index::DatasetsIndex,
+        layout::DataChunk,
         manager::{self, StateManager},
     },
     types::{dataset::Dataset, state::ChunkSet},
-    util::UseOnce,
+    util::{
+        fair_queue::FairQueue, rate_limiter::RateLimiter, readiness::Readiness,
+        supervisor::Supervisor,
+    },
 };
 
-lazy_static::lazy_static! {
-    static ref PARALLEL_QUERIES: usize = std::env::var("PARALLEL_QUERIES")
-        .map(|s| s.parse().expect("Invalid PARALLEL_QUERIES"))
-        .unwrap_or(3);
-    static ref QUEUED_QUERIES: usize = std::env::var("QUEUED_QUERIES")
-        .map(|s| s.parse().expect("Invalid QUEUED_QUERIES"))
-        .unwrap_or(15);
-}
+use super::lifecycle::{Lifecycle, LifecycleState};
 
 pub struct Worker<A: AllocationsChecker> {
     state_manager: Arc<StateManager>,
     // TODO: move allocation checking to the controller
     allocations_checker: A,
-    queries_tx: mpsc::Sender<QueryTask>,
-    queries_rx: UseOnce<mpsc::Receiver<QueryTask>>,
+    // Each client has its own queue, so that one client can't fill the queue for everyone
+    queue: FairQueue<Option<PeerId>, QueryTask>,
+    rate_limiter: Option<RateLimiter<Option<PeerId>>>,
+    parallel_queries: AtomicUsize,
+    query_timeout: Mutex<Duration>,
+    sql_enabled: bool,
+    sql_max_rows: AtomicUsize,
+    result_cache: Option<Arc<ResultCache>>,
+    executor: QueryExecutor,
+    // Wakes up the queries loop when the concurrency limit changes
+    limits_changed: Notify,
+    running_queries: AtomicUsize,
+    readiness: Readiness,
+    lifecycle: Lifecycle,
+    last_pong: Mutex<Option<std::time::Instant>>,
+    started_at: std::time::Instant,
     pub peer_id: Option<PeerId>,
 }
 
+pub struct QueriesStatus {
+    pub running: usize,
+    pub queued: usize,
+}
+
+/// Settings of a single query chosen by the caller
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Can only be lower than the configured query timeout
+    pub timeout: Option<Duration>,
+    /// Overrides the format requested in the query
+    pub format: Option<ResultFormat>,
+    /// Overrides the compression requested in the query
+    pub compression: Option<Compression>,
+    pub kind: QueryKind,
+    /// Return the plan of profiled queries in a `{ "data", "execPlan" }` JSON envelope
+    /// instead of a separate field of the result
+    pub embed_exec_plan: bool,
+}
+
+/// How the query string should be parsed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueryKind {
+    #[default]
+    Batch,
+    /// Ad-hoc [`SqlRequest`], only accepted if SQL queries are enabled
+    Sql,
+}
+
+enum ParsedQuery {
+    Batch(BatchRequest),
+    Sql(SqlRequest),
+}
+
 pub struct QueryTask {
     pub dataset: Dataset,
     pub query_str: String,
     pub client_id: Option<PeerId>,
+    pub options: QueryOptions,
+    pub deadline: Instant,
     pub response_sender: oneshot::Sender<Result<QueryResult, QueryError>>,
 }
 
 impl<A: AllocationsChecker> Worker<A> {
-    pub fn new(state_manager: StateManager, allocations_checker: A) -> Self {
-        let (queries_tx, queries_rx) = mpsc::channel(*QUEUED_QUERIES);
+    pub fn new(
+        state_manager: StateManager,
+        allocations_checker: A,
+        executor: QueryExecutor,
+        config: &QueriesConfig,
+    ) -> Self {
         Self {
             state_manager: Arc::new(state_manager),
             allocations_checker,
-            queries_tx,
-            queries_rx: UseOnce::new(queries_rx),
+            queue: FairQueue::new(config.queued_queries, config.total_queued_queries),
+            rate_limiter: config
+                .client_rate_limit
+                .map(|rate| RateLimiter::new(rate, rate)),
+            parallel_queries: AtomicUsize::new(config.parallel_queries),
+            query_timeout: Mutex::new(config.timeout()),
+            sql_enabled: config.sql_enabled,
+            sql_max_rows: AtomicUsize::new(config.sql_max_rows),
+            result_cache: None,
+            executor,
+            limits_changed: Notify::new(),
+            running_queries: AtomicUsize::new(0),
+            readiness: Default::default(),
+            lifecycle: Lifecycle::new(LifecycleState::Starting),
+            last_pong: Mutex::new(None),
+            started_at: std::time::Instant::now(),
             peer_id: None,
         }
     }
@@ -61,6 +147,32 @@ impl<A: AllocationsChecker> Worker<A> {
         self
     }
 
+    pub fn with_result_cache(mut self, cache: ResultCache) -> Self {
+        let cache = Arc::new(cache);
+        self.state_manager.on_chunk_removed({
+            let cache = cache.clone();
+            move |chunk| cache.invalidate_chunk(chunk)
+        });
+        self.result_cache = Some(cache);
+        self
+    }
+
+    /// Applies the settings that can be changed at runtime.
+    /// Queries that are already queued or running are not affected.
+    pub fn reload_config(&self, config: &Config) {
+        self.parallel_queries
+            .store(config.queries.parallel_queries, Ordering::Relaxed);
+        self.queue.set_capacity(
+            config.queries.queued_queries,
+            config.queries.total_queued_queries,
+        );
+        *self.query_timeout.lock() = config.queries.timeout();
+        self.sql_max_rows
+            .store(config.queries.sql_max_rows, Ordering::Relaxed);
+        self.limits_changed.notify_one();
+        self.state_manager.reload_config(config.downloads.clone());
+    }
+
     pub fn set_desired_chunks(&self, chunks: ChunkSet) {
         self.state_manager.set_desired_chunks(chunks);
     }
@@ -73,101 +185,331 @@ impl<A: AllocationsChecker> Worker<A> {
         self.state_manager.stop_downloads();
     }
 
+    pub fn state_manager(&self) -> &StateManager {
+        &self.state_manager
+    }
+
     pub fn status(&self) -> manager::Status {
         self.state_manager.current_status()
     }
 
+    pub fn readiness(&self) -> &Readiness {
+        &self.readiness
+    }
+
+    pub fn lifecycle(&self) -> &Lifecycle {
+        &self.lifecycle
+    }
+
+    pub fn record_pong(&self) {
+        *self.last_pong.lock() = Some(std::time::Instant::now());
+    }
+
+    /// Time elapsed since the last response from the scheduler
+    pub fn last_pong_elapsed(&self) -> Option<Duration> {
+        self.last_pong
+            .lock()
+            .map(|received_at| received_at.elapsed())
+    }
+
+    pub fn sql_enabled(&self) -> bool {
+        self.sql_enabled
+    }
+
+    pub fn queries_status(&self) -> QueriesStatus {
+        QueriesStatus {
+            running: self.running_queries.load(Ordering::Relaxed),
+            queued: self.queue.total_len(),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
     pub fn schedule_query(
         &self,
         query_str: String,
         dataset: Dataset,
         client_id: Option<PeerId>,
-    ) -> Option<impl Future<Output = Result<QueryResult, QueryError>> + '_> {
+        options: QueryOptions,
+    ) -> Result<impl Future<Output = Result<QueryResult, QueryError>> + '_, QueryError> {
+        let state = self.lifecycle.state();
+        if !state.accepts_queries() {
+            return Err(QueryError::NotServing(state.to_string()));
+        }
+        if options.kind == QueryKind::Sql && !self.sql_enabled {
+            return Err(QueryError::BadRequest(
+                "SQL queries are disabled".to_owned(),
+            ));
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if !rate_limiter.try_acquire(client_id) {
+                tracing::debug!("Rate limit exceeded for {}", client_label(client_id));
+                return Err(QueryError::ServiceOverloaded);
+            }
+        }
+        // Queries without an allocation would be rejected after waiting in the queue anyway
+        let Some(weight) = self.allocations_checker.queue_weight(client_id) else {
+            return Err(QueryError::NoAllocation);
+        };
         let (resp_tx, resp_rx) = oneshot::channel();
-        match self.queries_tx.try_send(QueryTask {
+        let max_timeout = *self.query_timeout.lock();
+        let timeout = options
+            .timeout
+            .map_or(max_timeout, |timeout| timeout.min(max_timeout));
+        let task = QueryTask {
             dataset,
             query_str,
             client_id,
+            options,
+            deadline: Instant::now() + timeout,
             response_sender: resp_tx,
-        }) {
-            Err(mpsc::error::TrySendError::Full(_)) => {
-                return None;
-            }
-            Err(mpsc::error::TrySendError::Closed(_)) => {
-                panic!("Query subscriber dropped");
+        };
+        match self.queue.try_push(client_id, weight, task) {
+            Err(_) => {
+                return Err(QueryError::ServiceOverloaded);
             }
-            Ok(_) => {
+            Ok(queue_len) => {
                 metrics::PENDING_QUERIES.inc();
+                metrics::set_queued_queries(&client_label(client_id), queue_len);
             }
         };
-        Some(async move {
+        // Dropping this future cancels the query
+        Ok(async move {
             resp_rx
                 .await
                 .expect("Query processor didn't produce a result")
         })
     }
 
-    pub async fn run(&self, cancellation_token: CancellationToken) {
-        let queries_rx = self.queries_rx.take().unwrap();
-        let state_manager = self.state_manager.clone();
-        let state_manager_fut = state_manager.run(cancellation_token.child_token());
-        let worker_fut = ReceiverStream::new(queries_rx)
-            .take_until(cancellation_token.cancelled_owned())
-            .for_each_concurrent(*PARALLEL_QUERIES, |query_task| async move {
-                metrics::PENDING_QUERIES.dec();
-                tracing::debug!(
-                    "Running query from {}",
-                    query_task
-                        .client_id
-                        .map(|id| id.to_string())
-                        .unwrap_or("{unknown}".to_string())
-                );
-                let result = match self
-                    .allocations_checker
-                    .try_spend(query_task.client_id)
-                    .await
-                {
-                    Ok(gateway_allocations::Status::Spent) => {
-                        self.execute_query(query_task.query_str, query_task.dataset)
-                            .await
+    pub async fn run(&self, cancellation_token: CancellationToken) -> Result<()> {
+        Supervisor::new(cancellation_token.clone())
+            .with_task(
+                "state_manager",
+                self.state_manager
+                    .run(cancellation_token.child_token())
+                    .map(Ok),
+            )
+            .with_task(
+                "allocations_checker",
+                self.allocations_checker
+                    .run(cancellation_token.child_token())
+                    .map(Ok),
+            )
+            .with_task(
+                "queries",
+                self.run_queries_loop(cancellation_token.child_token())
+                    .map(Ok),
+            )
+            .run()
+            .await
+    }
+
+    async fn run_queries_loop(&self, cancellation_token: CancellationToken) {
+        let mut running = FuturesUnordered::new();
+        loop {
+            let parallel_queries = self.parallel_queries.load(Ordering::Relaxed);
+            tokio::select! {
+                (_, query_task) = self.queue.pop(), if running.len() < parallel_queries => {
+                    running.push(self.run_query(query_task));
+                }
+                Some(()) = running.next() => {}
+                _ = self.limits_changed.notified() => {}
+                _ = cancellation_token.cancelled() => { break; }
+            }
+            self.running_queries.store(running.len(), Ordering::Relaxed);
+        }
+        // Let the running queries finish
+        while running.next().await.is_some() {}
+    }
+
+    async fn run_query(&self, mut query_task: QueryTask) {
+        let client = client_label(query_task.client_id);
+        metrics::PENDING_QUERIES.dec();
+        metrics::set_queued_queries(&client, self.queue.len(&query_task.client_id));
+        if query_task.response_sender.is_closed() {
+            tracing::debug!("Query from {client} was cancelled before execution");
+            return;
+        }
+        if query_task.deadline <= Instant::now() {
+            tracing::debug!("Query from {client} timed out in the queue");
+            let _ = query_task.response_sender.send(Err(QueryError::Timeout));
+            return;
+        }
+        tracing::debug!("Running query from {client}");
+        // Invalid queries are rejected before reserving CUs
+        let query_str = &query_task.query_str;
+        let parsed = match query_task.options.kind {
+            QueryKind::Batch => serde_json::from_str(query_str).map(ParsedQuery::Batch),
+            QueryKind::Sql => serde_json::from_str(query_str).map(ParsedQuery::Sql),
+        };
+        let query = match parsed {
+            Ok(query) => query,
+            Err(e) => {
+                let error = QueryError::BadRequest(format!("Couldn't parse query: {e:?}"));
+                let _ = query_task.response_sender.send(Err(error));
+                return;
+            }
+        };
+        let estimate = match &query {
+            ParsedQuery::Batch(query) => query.cu_estimate,
+            ParsedQuery::Sql(_) => None,
+        };
+        let result = match self
+            .allocations_checker
+            .try_spend(query_task.client_id, estimate)
+            .await
+        {
+            Ok(gateway_allocations::Status::Spent) => {
+                let execution = match query {
+                    ParsedQuery::Batch(query) => self
+                        .execute_query(query, query_task.dataset, query_task.options)
+                        .left_future(),
+                    ParsedQuery::Sql(request) => {
+                        self.execute_sql(query_task.dataset, request).right_future()
                     }
-                    Ok(gateway_allocations::Status::NotEnoughCU) => Err(QueryError::NoAllocation),
-                    Err(e) => panic!("Couldn't check CU allocations: {e:?}"),
                 };
-                if query_task.response_sender.send(result).is_err() {
-                    tracing::error!("Query result couldn't be sent");
-                }
-            });
-        // TODO: cancel all the tasks if one of them finishes
-        tokio::join!(state_manager_fut, worker_fut,);
+                let result = tokio::select! {
+                    result = execution => result,
+                    _ = tokio::time::sleep_until(query_task.deadline) => {
+                        Err(QueryError::Timeout)
+                    },
+                    _ = query_task.response_sender.closed() => {
+                        // The reserved estimate stays spent
+                        tracing::debug!("Query from {client} was cancelled");
+                        return;
+                    },
+                };
+                self.allocations_checker
+                    .settle(query_task.client_id, estimate, &result);
+                result
+            }
+            Ok(gateway_allocations::Status::NotEnoughCU) => Err(QueryError::NoAllocation),
+            Err(e) => panic!("Couldn't check CU allocations: {e:?}"),
+        };
+        if query_task.response_sender.send(result).is_err() {
+            tracing::debug!("Query result couldn't be sent, the client is gone");
+        }
     }
 
     // TODO: process all chunks, not only the first one
     async fn execute_query(
         &self,
-        query_str: String,
+        query: BatchRequest,
         dataset: String,
+        options: QueryOptions,
     ) -> Result<QueryResult, QueryError> {
-        let query: BatchRequest = serde_json::from_str(query_str.as_str())
-            .map_err(|e| QueryError::BadRequest(format!("Couldn't parse query: {e:?}")))?;
-        let chunks_guard = self
-            .state_manager
-            .find_chunks(&dataset, (query.from_block as u32).into())?;
+        // Chunks are selected by time using the block timestamps of the available chunks.
+        // If none of them overlaps the requested time range, the query is not found.
+        let from_block = match query.from_timestamp {
+            Some(timestamp) => self
+                .state_manager
+                .find_block_by_timestamp(&dataset, timestamp, query.to_timestamp)?
+                .ok_or(QueryError::NotFound)?
+                .max(query.from_block.into()),
+            None => query.from_block.into(),
+        };
+        let chunks_guard = self.state_manager.find_chunks(&dataset, from_block)?;
+        let encoding = Encoding {
+            format: options.format.or(query.format).unwrap_or_default(),
+            compression: options
+                .compression
+                .or(query.compression)
+                .unwrap_or_default(),
+            compression_level: query.compression_level,
+            embed_exec_plan: options.embed_exec_plan,
+        };
+        if query.profile && encoding.embed_exec_plan && encoding.format != ResultFormat::Json {
+            return Err(QueryError::BadRequest(
+                "Profiled queries can only return JSON".to_owned(),
+            ));
+        }
         let path = chunks_guard.iter().next().cloned();
         if let Some(path) = path {
-            tokio::spawn(async move {
-                let ctx = query::context::prepare_query_context(&path).await.unwrap();
-                let result = query::processor::process_query(&ctx, query).await?;
-                Ok(QueryResult::new(result, 1)?)
-            })
-            .await
-            .unwrap_or_else(|e| {
+            // Profiled queries are always executed to get the actual metrics
+            let cache = self.result_cache.as_ref().filter(|_| !query.profile);
+            let cached = cache.map(|cache| {
+                let key = cache_key(&query, &dataset, std::slice::from_ref(&path), &encoding);
+                (cache, key, path.clone())
+            });
+            if let Some((cache, key, _)) = &cached {
+                if let Some(result) = cache.get(key).await {
+                    tracing::debug!("Returning cached result for query {key}");
+                    return Ok(result);
+                }
+            }
+            let ctx = self.executor.session_context();
+            let handle = self.executor.spawn(async move {
+                query::context::register_chunk(&ctx, &path).await?;
+                let result = match encoding.format {
+                    ResultFormat::Json => {
+                        let output = query::processor::process_query(&ctx, query).await?;
+                        QueryResult::new(output, encoding, 1)?
+                    }
+                    _ => {
+                        let output = query::processor::process_query_tables(&ctx, query).await?;
+                        QueryResult::from_tables(output, encoding, 1)?
+                    }
+                };
+                Ok(result)
+            });
+            // Stop processing if the query gets cancelled or times out
+            let _abort_guard = scopeguard::guard(handle.abort_handle(), |handle| handle.abort());
+            let result = handle.await.unwrap_or_else(|e| {
                 Err(QueryError::Other(
                     anyhow::Error::new(e).context("Query processing task panicked"),
                 ))
-            })
+            });
+            if let (Some((cache, key, path)), Ok(result)) = (cached, &result) {
+                cache.insert(key, vec![path], result).await;
+            }
+            result
         } else {
             Err(QueryError::NotFound)
         }
     }
+
+    /// Runs an ad-hoc SQL query on a single chunk, returning the JSON array of rows.
+    /// The requested range has to end within the chunk containing its first block.
+    async fn execute_sql(
+        &self,
+        dataset: Dataset,
+        request: SqlRequest,
+    ) -> Result<QueryResult, QueryError> {
+        let max_rows = self.sql_max_rows.load(Ordering::Relaxed);
+        let chunks_guard = self
+            .state_manager
+            .find_chunks(&dataset, request.from_block.into())?;
+        let Some(path) = chunks_guard.iter().next().cloned() else {
+            return Err(QueryError::NotFound);
+        };
+        let last_block = *DataChunk::from_path(path.as_str())?.last_block;
+        if request
+            .to_block
+            .map_or(true, |to_block| to_block > last_block)
+        {
+            return Err(QueryError::BadRequest(format!(
+                "SQL queries can't span several chunks, toBlock should be at most {last_block}"
+            )));
+        }
+        let ctx = self.executor.session_context();
+        let handle = self.executor.spawn(async move {
+            let schema = query::context::register_chunk(&ctx, &path).await?;
+            let rows = query::sql::process_sql(&ctx, &schema, &request, max_rows).await?;
+            Ok(QueryResult::from_rows(&rows, 1)?)
+        });
+        let _abort_guard = scopeguard::guard(handle.abort_handle(), |handle| handle.abort());
+        handle.await.unwrap_or_else(|e| {
+            Err(QueryError::Other(
+                anyhow::Error::new(e).context("SQL query task panicked"),
+            ))
+        })
+    }
+}
+
+fn client_label(client_id: Option<PeerId>) -> String {
+    client_id
+        .map(|id| id.to_string())
+        .unwrap_or("{unknown}".to_string())
 }
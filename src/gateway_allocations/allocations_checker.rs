@@ -7,15 +7,41 @@ use subsquid_network_transport::PeerId;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn};
 
-use super::{compute_units_storage::ComputeUnitsStorage, Status};
+use crate::{
+    metrics,
+    query::{error::QueryError, result::QueryResult},
+};
 
-const SINGLE_EXECUTION_COST: u64 = 1;
+use super::{
+    allocations_db::AllocationsDb, compute_units_storage::ComputeUnitsStorage,
+    cost_model::CostModel, Status,
+};
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Gives the RPC about 5 minutes to become available on startup
+const STARTUP_ATTEMPTS: u32 = 14;
 
 #[async_trait]
 pub trait AllocationsChecker: Sync + Send {
     async fn run(&self, cancellation_token: CancellationToken);
 
-    async fn try_spend(&self, gateway_id: Option<PeerId>) -> Result<Status>;
+    /// Reserves the estimated cost of the query before executing it.
+    /// The gateway may request its own estimate, see [`CostModel::estimate`].
+    async fn try_spend(&self, gateway_id: Option<PeerId>, estimate: Option<u64>) -> Result<Status>;
+
+    /// Replaces the reserved estimate with the actual cost of the executed query.
+    /// `estimate` should be the same as passed to `try_spend`.
+    fn settle(
+        &self,
+        gateway_id: Option<PeerId>,
+        estimate: Option<u64>,
+        result: &Result<QueryResult, QueryError>,
+    );
+
+    /// Share of the worker's query processing capacity the gateway is entitled to.
+    /// `None` if the gateway has no allocation, so its queries shouldn't be queued.
+    fn queue_weight(&self, gateway_id: Option<PeerId>) -> Option<u64>;
 }
 
 pub struct NoopAllocationsChecker {}
@@ -26,77 +52,451 @@ impl AllocationsChecker for NoopAllocationsChecker {
         cancellation_token.cancelled_owned().await;
     }
 
-    async fn try_spend(&self, _gateway_id: Option<PeerId>) -> Result<Status> {
+    async fn try_spend(
+        &self,
+        _gateway_id: Option<PeerId>,
+        _estimate: Option<u64>,
+    ) -> Result<Status> {
         Ok(Status::Spent)
     }
+
+    fn settle(
+        &self,
+        _gateway_id: Option<PeerId>,
+        _estimate: Option<u64>,
+        _result: &Result<QueryResult, QueryError>,
+    ) {
+    }
+
+    fn queue_weight(&self, _gateway_id: Option<PeerId>) -> Option<u64> {
+        Some(1)
+    }
 }
 
 pub struct RpcAllocationsChecker {
     client: Box<dyn contract_client::Client>,
     own_id: contract_client::U256,
     storage: Mutex<ComputeUnitsStorage>,
+    db: AllocationsDb,
+    cost_model: CostModel,
     polling_interval: Duration,
 }
 
 impl RpcAllocationsChecker {
+    /// Fetches allocations for the current epoch before returning,
+    /// so that the queries can be served right away.
+    /// The RPC calls are retried with exponential backoff in case it's temporarily unavailable.
+    /// CUs spent before the restart are restored if the epoch hasn't changed.
+    /// Fails without waiting for the retries if the token gets cancelled.
     pub async fn new(
         client: Box<dyn contract_client::Client>,
         peer_id: PeerId,
         polling_interval: Duration,
+        db: AllocationsDb,
+        cost_model: CostModel,
+        cancellation_token: &CancellationToken,
     ) -> Result<Self> {
-        let own_id = client.worker_id(peer_id).await?;
-        Ok(Self {
+        let own_id = with_retries("worker id", cancellation_token, || {
+            client.worker_id(peer_id)
+        })
+        .await?;
+        let epoch = with_retries("current epoch", cancellation_token, || {
+            client.current_epoch()
+        })
+        .await?;
+        let checker = Self {
             client,
             own_id,
             storage: Default::default(),
+            db,
+            cost_model,
             polling_interval,
+        };
+        with_retries("gateway allocations", cancellation_token, || {
+            checker.update_allocations(epoch)
         })
+        .await?;
+        if let Some(spent) = checker.db.load().await? {
+            if checker.storage.lock().restore_spent_cus(spent) {
+                info!("Restored spent CUs for epoch {epoch}");
+            }
+        }
+        Ok(checker)
+    }
+
+    async fn check_epoch(&self) -> Result<()> {
+        debug!("Getting current epoch");
+        let epoch = self.client.current_epoch().await?;
+        if epoch > self.storage.lock().epoch() {
+            info!("New epoch {epoch} started. Updating allocations");
+            self.update_allocations(epoch).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_allocations(&self, epoch: u32) -> Result<()> {
+        let clusters = self.client.gateway_clusters(self.own_id).await?;
+        self.storage.lock().update_allocations(epoch, clusters);
+        Ok(())
+    }
+
+    async fn flush(&self) {
+        let Some(snapshot) = self.storage.lock().take_snapshot() else {
+            return;
+        };
+        if let Err(e) = self.db.save(snapshot).await {
+            warn!("Couldn't save spent CUs: {e:?}");
+            self.storage.lock().mark_dirty();
+        }
+    }
+}
+
+async fn with_retries<T, E, F>(
+    what: &str,
+    cancellation_token: &CancellationToken,
+    mut f: impl FnMut() -> F,
+) -> Result<T>
+where
+    F: std::future::Future<Output = Result<T, E>>,
+    E: Into<anyhow::Error>,
+{
+    let mut delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let result = tokio::select! {
+            result = f() => result,
+            _ = cancellation_token.cancelled() => anyhow::bail!("Cancelled getting {what}"),
+        };
+        match result {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < STARTUP_ATTEMPTS => {
+                warn!("Couldn't get {what}, retrying in {delay:?}: {:?}", e.into());
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = cancellation_token.cancelled() => anyhow::bail!("Cancelled getting {what}"),
+                }
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into().context(format!("Couldn't get {what}"))),
+        }
     }
 }
 
 #[async_trait]
 impl AllocationsChecker for RpcAllocationsChecker {
     async fn run(&self, cancellation_token: CancellationToken) {
-        let mut current_epoch = 0;
-
-        let mut timer = tokio::time::interval(self.polling_interval);
+        let mut timer = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.polling_interval,
+            self.polling_interval,
+        );
         timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut flush_timer = tokio::time::interval(FLUSH_INTERVAL);
+        flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
             tokio::select!(
-                _ = timer.tick() => {},
+                _ = timer.tick() => {
+                    if let Err(e) = self.check_epoch().await {
+                        warn!("Couldn't update gateway allocations: {e:?}");
+                    }
+                },
+                _ = flush_timer.tick() => {
+                    self.flush().await;
+                },
                 _ = cancellation_token.cancelled() => { break; },
             );
-
-            debug!("Getting current epoch");
-            let epoch = match self.client.current_epoch().await {
-                Ok(epoch) => epoch,
-                Err(e) => {
-                    warn!("Couldn't get current epoch: {e:?}");
-                    continue;
-                }
-            };
-            if epoch > current_epoch {
-                info!("New epoch started. Updating allocations");
-                let clusters = match self.client.gateway_clusters(self.own_id).await {
-                    Ok(clusters) => clusters,
-                    Err(e) => {
-                        warn!("Couldn't fetch gateway allocations: {e:?}");
-                        continue;
-                    }
-                };
-                self.storage.lock().update_allocations(clusters);
-                current_epoch = epoch;
-            }
         }
+        self.flush().await;
     }
 
-    async fn try_spend(&self, gateway_id: Option<PeerId>) -> Result<Status> {
+    async fn try_spend(&self, gateway_id: Option<PeerId>, estimate: Option<u64>) -> Result<Status> {
         match gateway_id {
             Some(gateway_id) => Ok(self
                 .storage
                 .lock()
-                .try_spend_cus(gateway_id, SINGLE_EXECUTION_COST)),
+                .try_spend_cus(gateway_id, self.cost_model.estimate(estimate))),
             None => Ok(Status::NotEnoughCU),
         }
     }
+
+    fn settle(
+        &self,
+        gateway_id: Option<PeerId>,
+        estimate: Option<u64>,
+        result: &Result<QueryResult, QueryError>,
+    ) {
+        let Some(gateway_id) = gateway_id else {
+            return;
+        };
+        let cost = match result {
+            Ok(result) => self.cost_model.cost(result),
+            Err(_) => self.cost_model.failure_cost(),
+        };
+        let operator = self.storage.lock().adjust_spent_cus(
+            gateway_id,
+            self.cost_model.estimate(estimate),
+            cost,
+        );
+        if let Some(operator) = operator {
+            metrics::cus_spent(&gateway_id, &operator, cost);
+        }
+    }
+
+    fn queue_weight(&self, gateway_id: Option<PeerId>) -> Option<u64> {
+        gateway_id
+            .and_then(|gateway_id| self.storage.lock().allocated_cus(gateway_id))
+            .map(|cus| cus.min(u64::MAX.into()).as_u64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+    use std::time::{Duration, SystemTime};
+
+    use async_trait::async_trait;
+    use contract_client::{Address, Allocation, ClientError, GatewayCluster, Worker, U256};
+    use parking_lot::Mutex;
+    use subsquid_network_transport::PeerId;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{AllocationsChecker, RpcAllocationsChecker};
+    use crate::gateway_allocations::allocations_db::AllocationsDb;
+    use crate::gateway_allocations::Status;
+
+    #[derive(Clone, Default)]
+    struct FakeClient {
+        epoch: Arc<AtomicU32>,
+        clusters: Arc<Mutex<Vec<(Address, Vec<PeerId>, u64)>>>,
+    }
+
+    #[async_trait]
+    impl contract_client::Client for FakeClient {
+        fn clone_client(&self) -> Box<dyn contract_client::Client> {
+            Box::new(self.clone())
+        }
+
+        async fn current_epoch(&self) -> Result<u32, ClientError> {
+            Ok(self.epoch.load(Ordering::SeqCst))
+        }
+
+        async fn current_epoch_start(&self) -> Result<SystemTime, ClientError> {
+            Ok(SystemTime::UNIX_EPOCH)
+        }
+
+        async fn worker_id(&self, _peer_id: PeerId) -> Result<U256, ClientError> {
+            Ok(1.into())
+        }
+
+        async fn active_workers(&self) -> Result<Vec<Worker>, ClientError> {
+            Ok(Vec::new())
+        }
+
+        async fn is_gateway_registered(&self, _gateway_id: PeerId) -> Result<bool, ClientError> {
+            Ok(true)
+        }
+
+        async fn active_gateways(&self) -> Result<Vec<PeerId>, ClientError> {
+            Ok(Vec::new())
+        }
+
+        async fn current_allocations(
+            &self,
+            _client_id: PeerId,
+            _workers: Option<Vec<Worker>>,
+        ) -> Result<Vec<Allocation>, ClientError> {
+            Ok(Vec::new())
+        }
+
+        async fn gateway_clusters(
+            &self,
+            _worker_id: U256,
+        ) -> Result<Vec<GatewayCluster>, ClientError> {
+            Ok(self
+                .clusters
+                .lock()
+                .iter()
+                .map(|(operator, gateways, cus)| GatewayCluster {
+                    operator_addr: *operator,
+                    gateway_ids: gateways.clone(),
+                    allocated_computation_units: (*cus).into(),
+                })
+                .collect())
+        }
+    }
+
+    async fn create_checker(client: &FakeClient, db: &AllocationsDb) -> RpcAllocationsChecker {
+        RpcAllocationsChecker::new(
+            Box::new(client.clone()),
+            PeerId::random(),
+            Duration::from_secs(60),
+            db.clone(),
+            Default::default(),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retries_cancelled() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            super::with_retries("value", &token, || async {
+                Err::<(), _>(anyhow::anyhow!("RPC is unavailable"))
+            }),
+        )
+        .await;
+        assert!(result.expect("Retries ignored cancellation").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_initial_allocations() {
+        let gateway = PeerId::random();
+        let client = FakeClient::default();
+        client.epoch.store(1, Ordering::SeqCst);
+        client
+            .clusters
+            .lock()
+            .push((Address::from_low_u64_be(1), vec![gateway], 2));
+
+        let db = AllocationsDb::new(":memory:").await.unwrap();
+        let checker = create_checker(&client, &db).await;
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::Spent
+        ));
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::Spent
+        ));
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::NotEnoughCU
+        ));
+        assert!(matches!(
+            checker
+                .try_spend(Some(PeerId::random()), None)
+                .await
+                .unwrap(),
+            Status::NotEnoughCU
+        ));
+        assert!(matches!(
+            checker.try_spend(None, None).await.unwrap(),
+            Status::NotEnoughCU
+        ));
+        assert_eq!(checker.queue_weight(Some(gateway)), Some(2));
+        assert_eq!(checker.queue_weight(Some(PeerId::random())), None);
+        assert_eq!(checker.queue_weight(None), None);
+    }
+
+    #[tokio::test]
+    async fn test_requested_estimate() {
+        let gateway = PeerId::random();
+        let client = FakeClient::default();
+        client.epoch.store(1, Ordering::SeqCst);
+        client
+            .clusters
+            .lock()
+            .push((Address::from_low_u64_be(1), vec![gateway], 10));
+
+        let db = AllocationsDb::new(":memory:").await.unwrap();
+        let checker = create_checker(&client, &db).await;
+        assert!(matches!(
+            checker.try_spend(Some(gateway), Some(11)).await.unwrap(),
+            Status::NotEnoughCU
+        ));
+        assert!(matches!(
+            checker.try_spend(Some(gateway), Some(8)).await.unwrap(),
+            Status::Spent
+        ));
+        // The query failed, so only the base cost is charged
+        checker.settle(
+            Some(gateway),
+            Some(8),
+            &Err(crate::query::error::QueryError::NotFound),
+        );
+        assert!(matches!(
+            checker.try_spend(Some(gateway), Some(9)).await.unwrap(),
+            Status::Spent
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_new_epoch() {
+        let gateway = PeerId::random();
+        let client = FakeClient::default();
+        client.epoch.store(1, Ordering::SeqCst);
+        client
+            .clusters
+            .lock()
+            .push((Address::from_low_u64_be(1), vec![gateway], 1));
+
+        let db = AllocationsDb::new(":memory:").await.unwrap();
+        let checker = create_checker(&client, &db).await;
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::Spent
+        ));
+
+        // Same epoch, allocations are not refreshed
+        checker.check_epoch().await.unwrap();
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::NotEnoughCU
+        ));
+
+        client.epoch.store(2, Ordering::SeqCst);
+        checker.check_epoch().await.unwrap();
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::Spent
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_restart() {
+        let gateway = PeerId::random();
+        let client = FakeClient::default();
+        client.epoch.store(1, Ordering::SeqCst);
+        client
+            .clusters
+            .lock()
+            .push((Address::from_low_u64_be(1), vec![gateway], 2));
+        let db = AllocationsDb::new(":memory:").await.unwrap();
+
+        let checker = create_checker(&client, &db).await;
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::Spent
+        ));
+        checker.flush().await;
+        drop(checker);
+
+        // Restarted within the same epoch
+        let checker = create_checker(&client, &db).await;
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::Spent
+        ));
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::NotEnoughCU
+        ));
+        checker.flush().await;
+        drop(checker);
+
+        // Restarted in the next epoch
+        client.epoch.store(2, Ordering::SeqCst);
+        let checker = create_checker(&client, &db).await;
+        assert!(matches!(
+            checker.try_spend(Some(gateway), None).await.unwrap(),
+            Status::Spent
+        ));
+    }
 }
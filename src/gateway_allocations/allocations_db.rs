@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use contract_client::{Address, U256};
+use tokio_rusqlite::{Connection, OptionalExtension};
+
+/// Keeps the compute units spent during the current epoch
+/// so that they are not lost when the worker restarts.
+#[derive(Clone)]
+pub struct AllocationsDb {
+    db: Connection,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SpentCUs {
+    pub epoch: u32,
+    pub operators: HashMap<Address, U256>,
+}
+
+impl AllocationsDb {
+    pub async fn new(db_path: &str) -> Result<Self> {
+        let db = Connection::open(db_path).await?;
+        db.call(|db| {
+            db.execute_batch(
+                r#"
+                BEGIN;
+                CREATE TABLE IF NOT EXISTS epoch(number INTEGER);
+                CREATE TABLE IF NOT EXISTS spent_cus(operator BLOB PRIMARY KEY, spent BLOB);
+                COMMIT;"#,
+            )?;
+            Ok(())
+        })
+        .await?;
+        Ok(Self { db })
+    }
+
+    pub async fn load(&self) -> Result<Option<SpentCUs>> {
+        let spent = self
+            .db
+            .call(|db| {
+                let epoch: Option<u32> = db
+                    .prepare_cached("SELECT number FROM epoch")?
+                    .query_row((), |row| row.get(0))
+                    .optional()?;
+                let Some(epoch) = epoch else {
+                    return Ok(None);
+                };
+                let operators = db
+                    .prepare_cached("SELECT operator, spent FROM spent_cus")?
+                    .query_map((), |row| {
+                        let operator: Vec<u8> = row.get(0)?;
+                        let spent: Vec<u8> = row.get(1)?;
+                        Ok((
+                            Address::from_slice(&operator),
+                            U256::from_big_endian(&spent),
+                        ))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(Some(SpentCUs { epoch, operators }))
+            })
+            .await?;
+        Ok(spent)
+    }
+
+    /// Replaces all the stored values with the given ones
+    pub async fn save(&self, spent: SpentCUs) -> Result<()> {
+        self.db
+            .call(move |db| {
+                let tx = db.transaction()?;
+                tx.execute("DELETE FROM epoch", ())?;
+                tx.execute("INSERT INTO epoch VALUES(?)", [spent.epoch])?;
+                tx.execute("DELETE FROM spent_cus", ())?;
+                {
+                    let mut stmt = tx.prepare_cached("INSERT INTO spent_cus VALUES(?, ?)")?;
+                    for (operator, cus) in spent.operators {
+                        let mut buf = [0u8; 32];
+                        cus.to_big_endian(&mut buf);
+                        stmt.execute((operator.as_bytes(), &buf[..]))?;
+                    }
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use contract_client::Address;
+
+    use super::{AllocationsDb, SpentCUs};
+
+    #[tokio::test]
+    async fn test_allocations_db() {
+        let db = AllocationsDb::new(":memory:").await.unwrap();
+        assert_eq!(db.load().await.unwrap(), None);
+
+        let spent = SpentCUs {
+            epoch: 5,
+            operators: [
+                (Address::from_low_u64_be(1), 10.into()),
+                (Address::from_low_u64_be(2), u64::MAX.into()),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        db.save(spent).await.unwrap();
+        let loaded = db.load().await.unwrap().unwrap();
+        assert_eq!(loaded.epoch, 5);
+        assert_eq!(loaded.operators.len(), 2);
+        assert_eq!(
+            loaded.operators[&Address::from_low_u64_be(2)],
+            u64::MAX.into()
+        );
+
+        db.save(SpentCUs {
+            epoch: 6,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            db.load().await.unwrap(),
+            Some(SpentCUs {
+                epoch: 6,
+                ..Default::default()
+            })
+        );
+    }
+}
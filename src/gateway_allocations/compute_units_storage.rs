@@ -3,10 +3,14 @@ use std::collections::HashMap;
 use contract_client::{Address, GatewayCluster, U256};
 use subsquid_network_transport::PeerId;
 
+use super::allocations_db::SpentCUs;
+
 #[derive(Default)]
 pub struct ComputeUnitsStorage {
+    epoch: u32,
     operators: HashMap<Address, Operator>,
     operator_by_gateway_id: HashMap<PeerId, Address>,
+    dirty: bool, // spent CUs changed since the last snapshot
 }
 
 pub enum Status {
@@ -20,7 +24,11 @@ struct Operator {
 }
 
 impl ComputeUnitsStorage {
-    pub fn update_allocations(&mut self, clusters: Vec<GatewayCluster>) {
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    pub fn update_allocations(&mut self, epoch: u32, clusters: Vec<GatewayCluster>) {
         self.operators.drain();
         self.operator_by_gateway_id.drain();
 
@@ -37,6 +45,21 @@ impl ComputeUnitsStorage {
                     .insert(gateway, cluster.operator_addr);
             }
         }
+        self.epoch = epoch;
+        self.dirty = true;
+    }
+
+    /// Restores spent CUs saved before the restart. Ignored if the epoch has changed since then.
+    pub fn restore_spent_cus(&mut self, spent: SpentCUs) -> bool {
+        if spent.epoch != self.epoch {
+            return false;
+        }
+        for (operator_id, spent_cus) in spent.operators {
+            if let Some(operator) = self.operators.get_mut(&operator_id) {
+                operator.spent_cus = spent_cus;
+            }
+        }
+        true
     }
 
     pub fn try_spend_cus(&mut self, gateway_id: PeerId, used_units: u64) -> Status {
@@ -47,11 +70,54 @@ impl ComputeUnitsStorage {
             }
         };
         let operator = self.operators.get_mut(operator_id).unwrap();
-        if operator.spent_cus + used_units < operator.allocated_cus {
+        if operator.spent_cus + used_units <= operator.allocated_cus {
             operator.spent_cus += used_units.into();
+            self.dirty = true;
             Status::Spent
         } else {
             Status::NotEnoughCU
         }
     }
+
+    pub fn allocated_cus(&self, gateway_id: PeerId) -> Option<U256> {
+        let operator_id = self.operator_by_gateway_id.get(&gateway_id)?;
+        self.operators
+            .get(operator_id)
+            .map(|operator| operator.allocated_cus)
+    }
+
+    /// Replaces previously reserved CUs with the actually spent ones.
+    /// The operator may end up over their allocation because the query has already been executed.
+    pub fn adjust_spent_cus(
+        &mut self,
+        gateway_id: PeerId,
+        reserved_units: u64,
+        used_units: u64,
+    ) -> Option<Address> {
+        let operator_id = *self.operator_by_gateway_id.get(&gateway_id)?;
+        let operator = self.operators.get_mut(&operator_id).unwrap();
+        operator.spent_cus = operator.spent_cus.saturating_sub(reserved_units.into()) + used_units;
+        self.dirty = true;
+        Some(operator_id)
+    }
+
+    /// Returns the current spent CUs if they have changed since the last call
+    pub fn take_snapshot(&mut self) -> Option<SpentCUs> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        Some(SpentCUs {
+            epoch: self.epoch,
+            operators: self
+                .operators
+                .iter()
+                .map(|(id, operator)| (*id, operator.spent_cus))
+                .collect(),
+        })
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
 }
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+use crate::query::result::QueryResult;
+
+const MB: u64 = 1 << 20;
+
+/// Defines how many compute units a query costs.
+/// The default model charges a flat fee of 1 CU per query.
+/// Gateways may reserve their own estimate for expensive queries,
+/// it is clamped between the minimal query cost and `max_estimate`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CostModel {
+    pub base_cost: u64,
+    pub per_chunk: u64,
+    pub per_scanned_mb: u64,
+    pub per_output_mb: u64,
+    /// Upper bound for the estimates requested by gateways
+    pub max_estimate: Option<u64>,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            base_cost: 1,
+            per_chunk: 0,
+            per_scanned_mb: 0,
+            per_output_mb: 0,
+            max_estimate: None,
+        }
+    }
+}
+
+impl CostModel {
+    /// Cost reserved before the query execution, optionally requested by the gateway.
+    /// Queries are currently executed on a single chunk.
+    pub fn estimate(&self, requested: Option<u64>) -> u64 {
+        let min = self.base_cost + self.per_chunk;
+        match requested {
+            Some(requested) => requested
+                .min(self.max_estimate.unwrap_or(u64::MAX))
+                .max(min),
+            None => min,
+        }
+    }
+
+    /// Actual cost of the executed query
+    pub fn cost(&self, result: &QueryResult) -> u64 {
+        self.base_cost
+            + self.per_chunk * result.num_read_chunks as u64
+            + self.per_scanned_mb * result.bytes_scanned.div_ceil(MB)
+            + self.per_output_mb * (result.data_size as u64).div_ceil(MB)
+    }
+
+    /// Cost of the query that failed to execute
+    pub fn failure_cost(&self) -> u64 {
+        self.base_cost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::result::QueryResult;
+
+    use super::{CostModel, MB};
+
+    fn query_result(num_read_chunks: usize, bytes_scanned: u64, data_size: usize) -> QueryResult {
+        QueryResult {
+            data: Default::default(),
+            data_size,
+            compressed_size: 0,
+            data_sha3_256: Vec::new(),
+            num_read_chunks,
+            bytes_scanned,
+            format: Default::default(),
+            compression: Default::default(),
+            exec_plan: None,
+        }
+    }
+
+    #[test]
+    fn test_default_cost() {
+        let model = CostModel::default();
+        assert_eq!(model.estimate(None), 1);
+        assert_eq!(model.estimate(Some(1000)), 1000);
+        assert_eq!(model.cost(&query_result(1, 100 * MB, 10 * MB as usize)), 1);
+    }
+
+    #[test]
+    fn test_cost() {
+        let model = CostModel {
+            base_cost: 2,
+            per_chunk: 3,
+            per_scanned_mb: 5,
+            per_output_mb: 7,
+            max_estimate: Some(100),
+        };
+        assert_eq!(model.estimate(None), 5);
+        assert_eq!(model.estimate(Some(1)), 5);
+        assert_eq!(model.estimate(Some(50)), 50);
+        assert_eq!(model.estimate(Some(1000)), 100);
+        assert_eq!(model.cost(&query_result(1, 0, 0)), 5);
+        assert_eq!(model.cost(&query_result(1, 1, 1)), 5 + 5 + 7);
+        assert_eq!(
+            model.cost(&query_result(2, 3 * MB, 2 * MB as usize + 1)),
+            2 + 2 * 3 + 3 * 5 + 3 * 7
+        );
+        assert_eq!(model.failure_cost(), 2);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod allocations_checker;
+pub mod allocations_db;
 pub mod compute_units_storage;
+pub mod cost_model;
 pub use compute_units_storage::Status;
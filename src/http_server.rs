@@ -1,42 +1,109 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use crate::{
-    cli::HttpArgs, controller::worker::Worker,
-    gateway_allocations::allocations_checker::AllocationsChecker, types::dataset::Dataset,
+    auth::{self, AuthError, Authenticator},
+    cli::HttpArgs,
+    controller::{
+        lifecycle::LifecycleState,
+        worker::{QueryKind, QueryOptions, Worker},
+    },
+    gateway_allocations::allocations_checker::AllocationsChecker,
+    query::{
+        compression::Compression, error::QueryError, format::ResultFormat, result::QueryResult,
+        sql::SqlRequest,
+    },
+    storage::{layout::DataChunk, state::ChunkStatus},
+    types::{dataset::Dataset, state::ChunkRef},
 };
 
 use axum::{
-    extract::Path,
-    http::{header, HeaderMap},
+    body::Bytes,
+    extract::{Path, Request},
+    http::{header, HeaderMap, HeaderValue, Method, Uri},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{get, post},
     Json,
 };
 use prometheus_client::{encoding::text::encode, registry::Registry};
 use reqwest::StatusCode;
+use serde::Deserialize;
+use subsquid_network_transport::PeerId;
 use tokio_util::sync::CancellationToken;
 
+const QUERY_TIMEOUT_HEADER: &str = "x-query-timeout-ms";
+
 async fn get_status(
     worker: Arc<Worker<impl AllocationsChecker>>,
     args: Option<HttpArgs>,
 ) -> Json<serde_json::Value> {
     let status = worker.status();
-    match args {
-        Some(args) => Json(serde_json::json!({
-            "router_url": args.router,
-            "worker_id": args.worker_id,
-            "worker_url": args.worker_url,
-            "state": {
-                "available": status.available,
-                "downloading": status.downloading,
-            }
-        })),
-        None => Json(serde_json::json!({
-            "state": {
-                "available": status.available,
-                "downloading": status.downloading,
-            }
-        })),
+    let datasets: serde_json::Map<_, _> = worker
+        .state_manager()
+        .dataset_stats()
+        .into_iter()
+        .map(|(dataset, stats)| {
+            let stats = serde_json::json!({
+                "available_chunks": stats.available_chunks,
+                "downloading_chunks": stats.downloading_chunks,
+                "pending_chunks": stats.pending_chunks,
+                "stored_bytes": stats.stored_bytes,
+            });
+            (dataset, stats)
+        })
+        .collect();
+    let queries = worker.queries_status();
+    let lifecycle = worker.lifecycle();
+    let state = lifecycle.state();
+    let mut result = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_sec": worker.uptime().as_secs(),
+        "ready": worker.readiness().is_ready(),
+        "state": {
+            "available": status.available,
+            "downloading": status.downloading,
+        },
+        "stored_bytes": status.stored_bytes,
+        "unsupported_chunks": status.unsupported_chunks,
+        "datasets": datasets,
+        "queries": {
+            "running": queries.running,
+            "queued": queries.queued,
+        },
+        "lifecycle": {
+            "state": state.as_str(),
+            "accepts_queries": state.accepts_queries(),
+            "jail_reason": match state {
+                LifecycleState::Jailed(reason) => Some(reason),
+                _ => None,
+            },
+            "since_sec": lifecycle.elapsed().as_secs(),
+            "last_pong_sec_ago": worker.last_pong_elapsed().map(|elapsed| elapsed.as_secs()),
+        },
+    });
+    if let Some(args) = args {
+        result["router_url"] = args.router.into();
+        result["worker_id"] = args.worker_id.into();
+        result["worker_url"] = args.worker_url.into();
+    }
+    Json(result)
+}
+
+/// The worker is alive as long as it can respond
+async fn get_liveness() -> StatusCode {
+    StatusCode::OK
+}
+
+async fn get_readiness(worker: Arc<Worker<impl AllocationsChecker>>) -> Response {
+    let unmet = worker.readiness().unmet_conditions();
+    if unmet.is_empty() {
+        StatusCode::OK.into_response()
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "unmet_conditions": unmet })),
+        )
+            .into_response()
     }
 }
 
@@ -49,16 +116,210 @@ async fn get_peer_id(worker: Arc<Worker<impl AllocationsChecker>>) -> (StatusCod
 
 async fn run_query(
     worker: Arc<Worker<impl AllocationsChecker>>,
+    auth: Arc<Authenticator>,
+    method: Method,
+    uri: Uri,
     Path(dataset): Path<Dataset>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let client_id = match auth.authenticate(&method, uri.path(), &headers, &body) {
+        Ok(client_id) => client_id,
+        Err(e) => return e.into_response(),
+    };
+    let query_str = match String::from_utf8(body.into()) {
+        Ok(query_str) => query_str,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Query is not valid UTF-8").into_response(),
+    };
+    let timeout = match headers.get(QUERY_TIMEOUT_HEADER).map(parse_timeout) {
+        Some(Ok(timeout)) => Some(timeout),
+        Some(Err(e)) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        None => None,
+    };
+    let header_str =
+        |name: header::HeaderName| headers.get(name).and_then(|value| value.to_str().ok());
+    let options = QueryOptions {
+        timeout,
+        format: header_str(header::ACCEPT).and_then(ResultFormat::from_accept),
+        compression: Some(
+            header_str(header::ACCEPT_ENCODING)
+                .map_or(Compression::None, Compression::from_accept_encoding),
+        ),
+        kind: QueryKind::Batch,
+        embed_exec_plan: true,
+    };
+    schedule_query(&worker, query_str, dataset, client_id, options).await
+}
+
+async fn schedule_query(
+    worker: &Worker<impl AllocationsChecker>,
     query_str: String,
+    dataset: Dataset,
+    client_id: Option<PeerId>,
+    options: QueryOptions,
 ) -> Response {
-    if let Some(future) = worker.schedule_query(query_str, dataset, None) {
-        future.await.map(|result| result.raw_data).into_response()
-    } else {
-        Response::builder()
+    // The future is dropped if the client disconnects, which cancels the query
+    match worker.schedule_query(query_str, dataset, client_id, options) {
+        Ok(future) => match future.await {
+            Ok(result) => query_response(result),
+            Err(e) => e.into_response(),
+        },
+        Err(QueryError::ServiceOverloaded) => Response::builder()
             .status(529)
             .body("Worker is overloaded".into())
-            .unwrap()
+            .unwrap(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn run_sql(
+    worker: Arc<Worker<impl AllocationsChecker>>,
+    auth: Arc<Authenticator>,
+    method: Method,
+    uri: Uri,
+    Path(dataset): Path<Dataset>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let client_id = match auth.authenticate(&method, uri.path(), &headers, &body) {
+        Ok(client_id) => client_id,
+        Err(e) => return e.into_response(),
+    };
+    // Invalid requests are rejected before they take a place in the queue
+    if let Err(e) = serde_json::from_slice::<SqlRequest>(&body) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Couldn't parse request: {e}"),
+        )
+            .into_response();
+    }
+    let query_str = match String::from_utf8(body.into()) {
+        Ok(query_str) => query_str,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Query is not valid UTF-8").into_response(),
+    };
+    let options = QueryOptions {
+        kind: QueryKind::Sql,
+        ..Default::default()
+    };
+    schedule_query(&worker, query_str, dataset, client_id, options).await
+}
+
+fn query_response(result: QueryResult) -> Response {
+    let mut response = (
+        [(header::CONTENT_TYPE, result.format.content_type())],
+        result.data,
+    )
+        .into_response();
+    if let Some(encoding) = result.compression.content_encoding() {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    }
+    response
+}
+
+fn parse_timeout(value: &HeaderValue) -> Result<Duration, String> {
+    value
+        .to_str()
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("Invalid {QUERY_TIMEOUT_HEADER} header"))
+}
+
+#[derive(Deserialize)]
+struct ChunkRequest {
+    dataset: String,
+    chunk: String,
+}
+
+async fn list_chunks(worker: Arc<Worker<impl AllocationsChecker>>) -> Json<serde_json::Value> {
+    let state_manager = worker.state_manager();
+    let chunks: Vec<_> = state_manager
+        .list_chunks()
+        .into_iter()
+        .map(|info| {
+            let size = (info.status == ChunkStatus::Available)
+                .then(|| state_manager.chunk_size(&info.chunk))
+                .flatten();
+            serde_json::json!({
+                "dataset": *info.chunk.dataset,
+                "chunk": info.chunk.chunk.path(),
+                "status": chunk_status_str(info.status),
+                "locks": info.locks,
+                "size_bytes": size,
+            })
+        })
+        .collect();
+    Json(serde_json::json!({ "chunks": chunks }))
+}
+
+async fn get_downloads(worker: Arc<Worker<impl AllocationsChecker>>) -> Json<serde_json::Value> {
+    let state_manager = worker.state_manager();
+    let chunks = state_manager.list_chunks();
+    let with_status = |status| {
+        chunks
+            .iter()
+            .filter(|info| info.status == status)
+            .map(|info| info.chunk.to_string())
+            .collect::<Vec<_>>()
+    };
+    Json(serde_json::json!({
+        "paused": state_manager.downloads_paused(),
+        "downloading": with_status(ChunkStatus::Downloading),
+        "pending": with_status(ChunkStatus::Pending),
+    }))
+}
+
+async fn evict_chunk(
+    worker: Arc<Worker<impl AllocationsChecker>>,
+    Json(request): Json<ChunkRequest>,
+    redownload: bool,
+) -> (StatusCode, String) {
+    let chunk = match DataChunk::from_path(&request.chunk) {
+        Ok(chunk) => chunk,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid chunk: {e}")),
+    };
+    let chunk = ChunkRef {
+        dataset: Arc::new(request.dataset),
+        chunk,
+    };
+    if worker.state_manager().evict_chunk(&chunk, redownload) {
+        (
+            StatusCode::OK,
+            format!("Chunk {chunk} scheduled for removal"),
+        )
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Chunk {chunk} is not available"),
+        )
+    }
+}
+
+async fn set_downloads_paused(
+    worker: Arc<Worker<impl AllocationsChecker>>,
+    paused: bool,
+) -> StatusCode {
+    worker.state_manager().set_downloads_paused(paused);
+    StatusCode::OK
+}
+
+async fn check_bearer_token(token: Arc<String>, request: Request, next: Next) -> Response {
+    let authorized = auth::bearer_token(request.headers())
+        .is_some_and(|provided| auth::constant_time_eq(provided, &token));
+    if authorized {
+        next.run(request).await
+    } else {
+        AuthError::Missing.into_response()
+    }
+}
+
+fn chunk_status_str(status: ChunkStatus) -> &'static str {
+    match status {
+        ChunkStatus::Available => "available",
+        ChunkStatus::Downloading => "downloading",
+        ChunkStatus::Pending => "pending",
     }
 }
 
@@ -91,8 +352,10 @@ impl Server {
         worker: Arc<Worker<impl AllocationsChecker + 'static>>,
         args: Option<HttpArgs>,
         metrics_registry: Registry,
+        auth: Authenticator,
     ) -> Self {
         let metrics_registry = Arc::new(metrics_registry);
+        let auth = Arc::new(auth);
         let router = axum::Router::new()
             .route(
                 "/worker/status",
@@ -101,6 +364,14 @@ impl Server {
                     move || get_status(worker, args)
                 }),
             )
+            .route("/health/live", get(get_liveness))
+            .route(
+                "/health/ready",
+                get({
+                    let worker = worker.clone();
+                    move || get_readiness(worker)
+                }),
+            )
             .route(
                 "/worker/peer-id",
                 get({
@@ -112,14 +383,91 @@ impl Server {
                 "/query/:dataset",
                 post({
                     let worker = worker.clone();
-                    move |path, body| run_query(worker, path, body)
+                    let auth = auth.clone();
+                    move |method, uri, path, headers, body| {
+                        run_query(worker, auth, method, uri, path, headers, body)
+                    }
+                }),
+            );
+        let router = if worker.sql_enabled() {
+            router.route(
+                "/sql/:dataset",
+                post({
+                    let worker = worker.clone();
+                    let auth = auth.clone();
+                    move |method, uri, path, headers, body| {
+                        run_sql(worker, auth, method, uri, path, headers, body)
+                    }
                 }),
             )
-            .route("/metrics", get(move || get_metrics(metrics_registry)));
+        } else {
+            router
+        };
+        let metrics_router =
+            axum::Router::new().route("/metrics", get(move || get_metrics(metrics_registry)));
+        let router = match auth.metrics_token() {
+            Some(token) => router.merge(Self::with_token(metrics_router, token)),
+            None => router.merge(metrics_router),
+        };
+        let router = match auth.admin_token() {
+            Some(token) => router.merge(Self::with_token(Self::admin_router(worker), token)),
+            None => router,
+        };
         let router = Self::add_common_layers(router);
         Self { router }
     }
 
+    fn admin_router(worker: Arc<Worker<impl AllocationsChecker + 'static>>) -> axum::Router {
+        axum::Router::new()
+            .route(
+                "/admin/chunks",
+                get({
+                    let worker = worker.clone();
+                    move || list_chunks(worker)
+                }),
+            )
+            .route(
+                "/admin/chunks/evict",
+                post({
+                    let worker = worker.clone();
+                    move |body| evict_chunk(worker, body, false)
+                }),
+            )
+            .route(
+                "/admin/chunks/redownload",
+                post({
+                    let worker = worker.clone();
+                    move |body| evict_chunk(worker, body, true)
+                }),
+            )
+            .route(
+                "/admin/downloads",
+                get({
+                    let worker = worker.clone();
+                    move || get_downloads(worker)
+                }),
+            )
+            .route(
+                "/admin/downloads/pause",
+                post({
+                    let worker = worker.clone();
+                    move || set_downloads_paused(worker, true)
+                }),
+            )
+            .route(
+                "/admin/downloads/resume",
+                post(move || set_downloads_paused(worker, false)),
+            )
+    }
+
+    /// Requires the `Authorization: Bearer <token>` header for all routes of the router
+    fn with_token(router: axum::Router, token: &str) -> axum::Router {
+        let token = Arc::new(token.to_owned());
+        router.route_layer(middleware::from_fn(move |request: Request, next: Next| {
+            check_bearer_token(token.clone(), request, next)
+        }))
+    }
+
     pub async fn run(self, port: u16, cancellation_token: CancellationToken) -> anyhow::Result<()> {
         let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
         axum::serve(listener, self.router)
@@ -1,4 +1,6 @@
+pub mod auth;
 pub mod cli;
+pub mod config;
 pub mod controller;
 pub mod gateway_allocations;
 pub mod http_server;
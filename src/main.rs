@@ -17,20 +17,30 @@
 use std::sync::Arc;
 
 use anyhow::Result;
+use camino::Utf8Path;
 use clap::Parser;
+use futures::FutureExt;
 use prometheus_client::metrics::info::Info;
 use subsquid_network_transport::P2PTransportBuilder;
 use subsquid_worker::controller::http::HttpController;
 use subsquid_worker::controller::p2p::create_p2p_controller;
 use subsquid_worker::controller::worker::Worker;
 use tokio_util::sync::CancellationToken;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use tracing::{info, warn};
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
 
-use subsquid_worker::cli::{self, Args, P2PArgs};
-use subsquid_worker::gateway_allocations::allocations_checker;
+use subsquid_worker::auth::Authenticator;
+use subsquid_worker::cli::{self, Args, ConfigArgs, P2PArgs};
+use subsquid_worker::config::{Config, SentryConfig};
+use subsquid_worker::gateway_allocations::allocations_checker::{self, AllocationsChecker};
+use subsquid_worker::gateway_allocations::allocations_db::AllocationsDb;
 use subsquid_worker::http_server::Server as HttpServer;
 use subsquid_worker::metrics;
+use subsquid_worker::query::{cache::ResultCache, executor::QueryExecutor};
 use subsquid_worker::storage::manager::StateManager;
+use subsquid_worker::util::supervisor::Supervisor;
 
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
@@ -39,30 +49,45 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-fn setup_tracing() -> Result<()> {
+type LogsFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+fn setup_tracing(config: &Config) -> Result<LogsFilterHandle> {
+    let (filter, handle) = reload::Layer::new(config.logs_filter());
     let fmt = tracing_subscriber::fmt::layer()
         .compact()
-        .with_filter(EnvFilter::from_default_env());
+        .with_filter(filter);
     tracing_subscriber::registry()
         .with(fmt)
         .with(sentry::integrations::tracing::layer())
         .try_init()?;
-    Ok(())
+    Ok(handle)
 }
 
-fn setup_sentry(args: &Args) -> Option<sentry::ClientInitGuard> {
-    args.sentry_dsn.as_ref().map(|dsn| {
+fn setup_sentry(config: &SentryConfig) -> Option<sentry::ClientInitGuard> {
+    config.dsn.as_ref().map(|dsn| {
         sentry::init((
             dsn.as_str(),
             sentry::ClientOptions {
                 release: sentry::release_name!(),
-                traces_sample_rate: args.sentry_traces_sample_rate,
+                traces_sample_rate: config.traces_sample_rate,
                 ..Default::default()
             },
         ))
     })
 }
 
+fn with_result_cache<A: AllocationsChecker>(
+    worker: Worker<A>,
+    config: &Config,
+    data_dir: &Utf8Path,
+) -> Result<Worker<A>> {
+    if !config.result_cache.enabled() {
+        return Ok(worker);
+    }
+    let cache = ResultCache::new(&config.result_cache, data_dir.join("result_cache"))?;
+    Ok(worker.with_result_cache(cache))
+}
+
 fn create_cancellation_token() -> Result<CancellationToken> {
     use tokio::signal::unix::{signal, SignalKind};
 
@@ -83,17 +108,61 @@ fn create_cancellation_token() -> Result<CancellationToken> {
     Ok(token)
 }
 
+/// Applies the settings that can be changed without a restart on SIGHUP
+async fn reload_config_on_sighup(
+    config_args: &ConfigArgs,
+    initial_config: &Config,
+    worker: &Worker<impl AllocationsChecker>,
+    logs_filter: &LogsFilterHandle,
+    cancellation_token: CancellationToken,
+) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select!(
+            _ = sighup.recv() => {},
+            _ = cancellation_token.cancelled() => { return Ok(()); },
+        );
+        info!("Reloading config");
+        let config = match config_args.load() {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Couldn't reload config: {e:?}");
+                continue;
+            }
+        };
+        for setting in initial_config.non_reloadable_changes(&config) {
+            warn!("Changes to {setting} will only be applied after a restart");
+        }
+        if let Err(e) = logs_filter.reload(config.logs_filter()) {
+            warn!("Couldn't update log level: {e:?}");
+        }
+        worker.reload_config(&config);
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
     let args = Args::parse();
-    setup_tracing()?;
-    let _sentry_guard = setup_sentry(&args);
+    let config = args.config.load()?;
+    if let cli::Mode::Config(cli::ConfigCommand::Print { show_secrets }) = args.mode {
+        let config = if show_secrets {
+            config
+        } else {
+            config.redacted()
+        };
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+    let logs_filter = setup_tracing(&config)?;
+    let _sentry_guard = setup_sentry(&config.sentry);
 
     let mut metrics_registry = Default::default();
 
     let state_manager =
-        StateManager::new(args.data_dir.join("worker"), args.concurrent_downloads).await?;
+        StateManager::new(args.data_dir.join("worker"), config.downloads.clone()).await?;
 
     let cancellation_token = create_cancellation_token()?;
 
@@ -104,30 +173,52 @@ async fn main() -> anyhow::Result<()> {
                 env!("CARGO_PKG_VERSION").to_owned(),
             )]);
             metrics::register_metrics(&mut metrics_registry, info);
-            let worker = Arc::new(Worker::new(
+            let worker = Worker::new(
                 state_manager,
                 allocations_checker::NoopAllocationsChecker {},
-            ));
+                QueryExecutor::new(&config.queries)?,
+                &config.queries,
+            );
+            let worker = Arc::new(with_result_cache(worker, &config, &args.data_dir)?);
             let controller = HttpController::new(
                 worker.clone(),
-                args.ping_interval,
+                config.network.ping_interval(),
                 http_args.worker_id.clone(),
                 http_args.worker_url.clone(),
                 http_args.router.clone(),
             );
-            let (_, server_result) = tokio::join!(
-                controller.run(cancellation_token.clone()),
-                tokio::spawn(
-                    HttpServer::new(worker, Some(http_args), metrics_registry)
-                        .run(args.port, cancellation_token.clone()),
+            Supervisor::new(cancellation_token.clone())
+                .with_task(
+                    "controller",
+                    controller.run(cancellation_token.clone()).map(Ok),
                 )
-            );
-            server_result??;
+                .with_task("worker", worker.run(cancellation_token.clone()))
+                .with_task(
+                    "config_reloader",
+                    reload_config_on_sighup(
+                        &args.config,
+                        &config,
+                        &worker,
+                        &logs_filter,
+                        cancellation_token.clone(),
+                    ),
+                )
+                .with_task(
+                    "http_server",
+                    HttpServer::new(
+                        worker.clone(),
+                        Some(http_args),
+                        metrics_registry,
+                        Authenticator::new(&config)?,
+                    )
+                    .run(args.port, cancellation_token.clone()),
+                )
+                .run()
+                .await?;
         }
         cli::Mode::P2P(P2PArgs {
             scheduler_id,
             logs_collector_id,
-            network_polling_interval,
             transport: transport_args,
             ..
         }) => {
@@ -141,14 +232,31 @@ async fn main() -> anyhow::Result<()> {
             metrics::register_metrics(&mut metrics_registry, info);
             metrics::register_p2p_metrics(&mut metrics_registry);
 
-            let allocations_checker = allocations_checker::RpcAllocationsChecker::new(
+            let allocations_db =
+                AllocationsDb::new(args.data_dir.join("allocations.db").as_str()).await?;
+            let allocations_checker = match allocations_checker::RpcAllocationsChecker::new(
                 transport_builder.contract_client(),
                 peer_id,
-                network_polling_interval,
+                config.network.network_polling_interval(),
+                allocations_db,
+                config.cost_model.clone(),
+                &cancellation_token,
             )
-            .await?;
-            let worker =
-                Arc::new(Worker::new(state_manager, allocations_checker).with_peer_id(peer_id));
+            .await
+            {
+                Ok(checker) => checker,
+                // Shutdown was requested while waiting for the RPC
+                Err(_) if cancellation_token.is_cancelled() => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let worker = Worker::new(
+                state_manager,
+                allocations_checker,
+                QueryExecutor::new(&config.queries)?,
+                &config.queries,
+            )
+            .with_peer_id(peer_id);
+            let worker = Arc::new(with_result_cache(worker, &config, &args.data_dir)?);
 
             let controller_fut = async {
                 tokio::select! {
@@ -160,23 +268,40 @@ async fn main() -> anyhow::Result<()> {
                         scheduler_id,
                         logs_collector_id,
                         args.data_dir,
-                        args.ping_interval,
+                        &config.network,
                     ) => {
-                        controller?.run(cancellation_token.clone()).await;
+                        controller?.run(cancellation_token.clone()).await?;
                     }
                 }
                 anyhow::Ok(())
             };
 
-            let (_, server_result) = tokio::join!(
-                controller_fut,
-                tokio::spawn(
-                    HttpServer::new(worker.clone(), None, metrics_registry)
-                        .run(args.port, cancellation_token.clone()),
+            Supervisor::new(cancellation_token.clone())
+                .with_task("controller", controller_fut)
+                .with_task(
+                    "config_reloader",
+                    reload_config_on_sighup(
+                        &args.config,
+                        &config,
+                        &worker,
+                        &logs_filter,
+                        cancellation_token.clone(),
+                    ),
                 )
-            );
-            server_result??;
+                .with_task(
+                    "http_server",
+                    HttpServer::new(
+                        worker.clone(),
+                        None,
+                        metrics_registry,
+                        Authenticator::new(&config)?,
+                    )
+                    .run(args.port, cancellation_token.clone()),
+                )
+                .run()
+                .await?;
         }
+        cli::Mode::Config(_) => unreachable!(),
     };
     Ok(())
 }
@@ -1,5 +1,6 @@
 use std::fmt::Write;
 
+use contract_client::Address;
 use prometheus_client::encoding::{EncodeLabelSet, LabelValueEncoder};
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::{family::Family, gauge::Gauge, histogram::Histogram, info::Info};
@@ -7,6 +8,7 @@ use prometheus_client::registry::{Registry, Unit};
 
 use crate::query::error::QueryError;
 use crate::query::result::QueryResult;
+use subsquid_network_transport::PeerId;
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub enum WorkerStatus {
@@ -15,6 +17,7 @@ pub enum WorkerStatus {
     UnsupportedVersion,
     Jailed,
     Active,
+    Stopping,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -23,6 +26,14 @@ pub enum QueryStatus {
     BadRequest,
     NoAllocation,
     ServerError,
+    Timeout,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum TaskExitReason {
+    Finished,
+    Failed,
+    Panicked,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
@@ -35,11 +46,29 @@ struct QueryExecutedLabels {
     status: QueryStatus,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ClientLabels {
+    client: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CUsSpentLabels {
+    gateway: String,
+    operator: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TaskExitedLabels {
+    task: String,
+    reason: TaskExitReason,
+}
+
 lazy_static::lazy_static! {
     static ref STATUS: Family<StatusLabels, Gauge> = Default::default();
     pub static ref CHUNKS_AVAILABLE: Gauge = Default::default();
     pub static ref CHUNKS_DOWNLOADING: Gauge = Default::default();
     pub static ref CHUNKS_PENDING: Gauge = Default::default();
+    pub static ref CHUNKS_UNSUPPORTED: Gauge = Default::default();
     pub static ref CHUNKS_DOWNLOADED: Counter = Default::default();
     pub static ref CHUNKS_FAILED_DOWNLOAD: Counter = Default::default();
     pub static ref CHUNKS_REMOVED: Counter = Default::default();
@@ -49,6 +78,10 @@ lazy_static::lazy_static! {
     static ref QUERY_RESULT_SIZE: Histogram = Histogram::new(std::iter::empty());
     static ref READ_CHUNKS: Histogram = Histogram::new(std::iter::empty());
     pub static ref PENDING_QUERIES: Gauge = Default::default();
+    static ref QUEUED_QUERIES: Family<ClientLabels, Gauge> = Default::default();
+    static ref CUS_SPENT: Family<CUsSpentLabels, Counter> = Default::default();
+
+    static ref TASK_EXITED: Family<TaskExitedLabels, Counter> = Default::default();
 }
 
 pub fn set_status(status: WorkerStatus) {
@@ -64,12 +97,13 @@ pub fn query_executed(result: &Result<QueryResult, QueryError>) {
     let (status, result) = match result {
         Ok(result) => (QueryStatus::Ok, Some(result)),
         Err(QueryError::NoAllocation) => (QueryStatus::NoAllocation, None),
-        Err(QueryError::NotFound | QueryError::BadRequest(_)) => {
-            (QueryStatus::BadRequest, None)
-        }
-        Err(QueryError::Other(_) | QueryError::ServiceOverloaded) => {
+        Err(
+            QueryError::NotFound | QueryError::BadRequest(_) | QueryError::MemoryLimitExceeded(_),
+        ) => (QueryStatus::BadRequest, None),
+        Err(QueryError::Other(_) | QueryError::ServiceOverloaded | QueryError::NotServing(_)) => {
             (QueryStatus::ServerError, None)
         }
+        Err(QueryError::Timeout) => (QueryStatus::Timeout, None),
     };
     QUERY_EXECUTED
         .get_or_create(&QueryExecutedLabels { status })
@@ -80,6 +114,37 @@ pub fn query_executed(result: &Result<QueryResult, QueryError>) {
     }
 }
 
+/// The client's gauge is removed once its queue is empty, so that the metrics of
+/// the clients that stopped sending queries don't pile up
+pub fn set_queued_queries(client: &str, queue_len: usize) {
+    let labels = ClientLabels {
+        client: client.to_owned(),
+    };
+    if queue_len == 0 {
+        QUEUED_QUERIES.remove(&labels);
+    } else {
+        QUEUED_QUERIES.get_or_create(&labels).set(queue_len as i64);
+    }
+}
+
+pub fn cus_spent(gateway_id: &PeerId, operator: &Address, units: u64) {
+    CUS_SPENT
+        .get_or_create(&CUsSpentLabels {
+            gateway: gateway_id.to_string(),
+            operator: format!("{operator:?}"),
+        })
+        .inc_by(units);
+}
+
+pub fn task_exited(task: &str, reason: TaskExitReason) {
+    TASK_EXITED
+        .get_or_create(&TaskExitedLabels {
+            task: task.to_owned(),
+            reason,
+        })
+        .inc();
+}
+
 pub fn register_metrics(registry: &mut Registry, info: Info<Vec<(String, String)>>) {
     registry.register("worker_info", "Worker info", info);
     registry.register(
@@ -97,6 +162,11 @@ pub fn register_metrics(registry: &mut Registry, info: Info<Vec<(String, String)
         "Number of chunks pending download",
         CHUNKS_PENDING.clone(),
     );
+    registry.register(
+        "chunks_unsupported",
+        "Number of assigned chunks not served because of block numbers above 2^32",
+        CHUNKS_UNSUPPORTED.clone(),
+    );
     registry.register(
         "chunks_downloaded",
         "Number of chunks downloaded",
@@ -135,6 +205,16 @@ pub fn register_metrics(registry: &mut Registry, info: Info<Vec<(String, String)
         "Number of chunks read during query execution",
         READ_CHUNKS.clone(),
     );
+    registry.register(
+        "queued_queries",
+        "Number of queued queries per client",
+        QUEUED_QUERIES.clone(),
+    );
+    registry.register(
+        "tasks_exited_unexpectedly",
+        "Number of background tasks that exited before shutdown",
+        TASK_EXITED.clone(),
+    );
 }
 
 pub fn register_p2p_metrics(registry: &mut Registry) {
@@ -145,6 +225,11 @@ pub fn register_p2p_metrics(registry: &mut Registry) {
         "Current size of the queries queue",
         PENDING_QUERIES.clone(),
     );
+    registry.register(
+        "cus_spent",
+        "Number of compute units spent by gateways",
+        CUS_SPENT.clone(),
+    );
 }
 
 impl prometheus_client::encoding::EncodeLabelValue for WorkerStatus {
@@ -155,6 +240,7 @@ impl prometheus_client::encoding::EncodeLabelValue for WorkerStatus {
             WorkerStatus::UnsupportedVersion => "unsupported_version",
             WorkerStatus::Jailed => "jailed",
             WorkerStatus::Active => "active",
+            WorkerStatus::Stopping => "stopping",
         };
         encoder.write_str(status)?;
         Ok(())
@@ -168,8 +254,21 @@ impl prometheus_client::encoding::EncodeLabelValue for QueryStatus {
             QueryStatus::BadRequest => "bad_request",
             QueryStatus::NoAllocation => "no_allocation",
             QueryStatus::ServerError => "server_error",
+            QueryStatus::Timeout => "timeout",
         };
         encoder.write_str(status)?;
         Ok(())
     }
 }
+
+impl prometheus_client::encoding::EncodeLabelValue for TaskExitReason {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        let reason = match self {
+            TaskExitReason::Finished => "finished",
+            TaskExitReason::Failed => "failed",
+            TaskExitReason::Panicked => "panicked",
+        };
+        encoder.write_str(reason)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{config::ResultCacheConfig, util::hash::sha3_256};
+
+use super::{
+    compression::Compression,
+    eth::BatchRequest,
+    format::ResultFormat,
+    result::{Encoding, QueryResult},
+};
+
+/// Identifies the query result by the normalized query, the dataset,
+/// the chunks it was executed on and the requested encoding
+pub fn cache_key(
+    query: &BatchRequest,
+    dataset: &str,
+    chunks: &[PathBuf],
+    encoding: &Encoding,
+) -> String {
+    let mut key = serde_json::to_vec(&normalize(query)).expect("Query should be serializable");
+    key.extend_from_slice(
+        format!(
+            "\n{}\n{dataset}\n{:?}\n{:?}\n{:?}",
+            env!("CARGO_PKG_VERSION"),
+            encoding.format,
+            encoding.compression,
+            encoding.compression_level
+        )
+        .as_bytes(),
+    );
+    for chunk in chunks {
+        key.push(b'\n');
+        key.extend_from_slice(chunk.as_str().as_bytes());
+    }
+    hex::encode(sha3_256(&key))
+}
+
+/// Hex values are matched case-insensitively and the filter lists are sets, so equivalent
+/// queries get the same key regardless of the case and order of the filter values.
+/// The field selections are kept as is because their order defines the order of the keys
+/// in the result, so only queries producing the same bytes share an entry.
+fn normalize(query: &BatchRequest) -> BatchRequest {
+    fn normalize_list(values: &mut Vec<String>) {
+        values
+            .iter_mut()
+            .for_each(|value| value.make_ascii_lowercase());
+        values.sort();
+        values.dedup();
+    }
+    let mut query = query.clone();
+    for request in query.blocks.iter_mut().flatten() {
+        request.miner.iter_mut().for_each(normalize_list);
+    }
+    for request in query.logs.iter_mut().flatten() {
+        for values in [
+            &mut request.address,
+            &mut request.topic0,
+            &mut request.topic1,
+            &mut request.topic2,
+            &mut request.topic3,
+        ] {
+            values.iter_mut().for_each(normalize_list);
+        }
+    }
+    for request in query.transactions.iter_mut().flatten() {
+        for values in [&mut request.from, &mut request.to, &mut request.sighash] {
+            values.iter_mut().for_each(normalize_list);
+        }
+    }
+    query
+}
+
+/// Keeps recently used query results in memory and, optionally, on disk.
+/// Cached results keep their original hash and size so that the query logs stay verifiable.
+/// The disk is accessed on the blocking threads pool.
+pub struct ResultCache {
+    memory: Mutex<Lru<QueryResult>>,
+    disk: Option<Arc<DiskCache>>,
+}
+
+struct DiskCache {
+    dir: PathBuf,
+    entries: Mutex<Lru<()>>,
+}
+
+/// Stored next to the result data on disk
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    chunks: Vec<String>,
+    data_size: usize,
+    data_sha3_256: String,
+    num_read_chunks: usize,
+    bytes_scanned: u64,
+    format: ResultFormat,
+    compression: Compression,
+}
+
+impl ResultCache {
+    pub fn new(config: &ResultCacheConfig, dir: PathBuf) -> Result<Self> {
+        let disk = if config.disk_mb > 0 {
+            Some(Arc::new(DiskCache::open(dir, config.disk_mb << 20)?))
+        } else {
+            None
+        };
+        Ok(Self {
+            memory: Mutex::new(Lru::new(config.memory_mb << 20)),
+            disk,
+        })
+    }
+
+    /// The returned result shares the data with the cached one
+    pub async fn get(&self, key: &str) -> Option<QueryResult> {
+        if let Some(result) = self.memory.lock().get(key) {
+            return Some(result.clone());
+        }
+        let disk = self.disk.clone()?;
+        let (result, chunks) = {
+            let key = key.to_owned();
+            tokio::task::spawn_blocking(move || disk.get(&key))
+                .await
+                .ok()
+                .flatten()?
+        };
+        self.memory.lock().insert(
+            key.to_owned(),
+            result.data.len() as u64,
+            chunks,
+            result.clone(),
+        );
+        Some(result)
+    }
+
+    pub async fn insert(&self, key: String, chunks: Vec<PathBuf>, result: &QueryResult) {
+        if let Some(disk) = &self.disk {
+            let disk = disk.clone();
+            let (key, chunks, result) = (key.clone(), chunks.clone(), result.clone());
+            match tokio::task::spawn_blocking(move || disk.insert(&key, chunks, &result)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Couldn't save query result to the cache: {e:?}"),
+                Err(e) => warn!("Saving query result to the cache panicked: {e:?}"),
+            }
+        }
+        self.memory
+            .lock()
+            .insert(key, result.data.len() as u64, chunks, result.clone());
+    }
+
+    /// Drops all results computed using the given chunk.
+    /// The files are removed in the background.
+    pub fn invalidate_chunk(&self, chunk: &Path) {
+        self.memory.lock().remove_by_chunk(chunk);
+        if let Some(disk) = &self.disk {
+            let keys = disk.entries.lock().remove_by_chunk(chunk);
+            if !keys.is_empty() {
+                let disk = disk.clone();
+                tokio::task::spawn_blocking(move || disk.evict(keys));
+            }
+        }
+    }
+}
+
+impl DiskCache {
+    fn open(dir: PathBuf, max_size: u64) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Couldn't create result cache dir {dir}"))?;
+        let cache = Self {
+            dir,
+            entries: Mutex::new(Lru::new(max_size)),
+        };
+        let mut loaded = 0;
+        for entry in cache.dir.read_dir_utf8()? {
+            let path = entry?.into_path();
+            let Some(key) = path.file_name().and_then(|name| name.strip_suffix(".json")) else {
+                continue;
+            };
+            match cache.read_metadata(key) {
+                Ok((metadata, size)) => {
+                    let chunks = metadata.chunks.into_iter().map(PathBuf::from).collect();
+                    let evicted = cache
+                        .entries
+                        .lock()
+                        .insert(key.to_owned(), size, chunks, ());
+                    cache.evict(evicted);
+                    loaded += 1;
+                }
+                Err(e) => {
+                    warn!("Removing invalid result cache entry {key}: {e:?}");
+                    cache.remove_files(key);
+                }
+            }
+        }
+        info!("Loaded {loaded} cached query results");
+        Ok(cache)
+    }
+
+    fn get(&self, key: &str) -> Option<(QueryResult, Vec<PathBuf>)> {
+        self.entries.lock().get(key)?;
+        let result = self.read(key).map_err(|e| {
+            warn!("Couldn't read cached query result {key}: {e:?}");
+            self.entries.lock().remove(key);
+            self.remove_files(key);
+        });
+        result.ok()
+    }
+
+    fn insert(&self, key: &str, chunks: Vec<PathBuf>, result: &QueryResult) -> Result<()> {
+        let metadata = Metadata {
+            chunks: chunks.iter().map(|chunk| chunk.to_string()).collect(),
+            data_size: result.data_size,
+            data_sha3_256: hex::encode(&result.data_sha3_256),
+            num_read_chunks: result.num_read_chunks,
+            bytes_scanned: result.bytes_scanned,
+            format: result.format,
+            compression: result.compression,
+        };
+        // The metadata is written last, so entries without it are never loaded
+        std::fs::write(self.data_path(key), &result.data)?;
+        std::fs::write(self.metadata_path(key), serde_json::to_vec(&metadata)?)?;
+        let evicted =
+            self.entries
+                .lock()
+                .insert(key.to_owned(), result.data.len() as u64, chunks, ());
+        self.evict(evicted);
+        Ok(())
+    }
+
+    fn read(&self, key: &str) -> Result<(QueryResult, Vec<PathBuf>)> {
+        let (metadata, _) = self.read_metadata(key)?;
+        let data = std::fs::read(self.data_path(key))?;
+        let result = QueryResult {
+            data_size: metadata.data_size,
+            compressed_size: data.len(),
+            data: data.into(),
+            data_sha3_256: hex::decode(metadata.data_sha3_256)?,
+            num_read_chunks: metadata.num_read_chunks,
+            bytes_scanned: metadata.bytes_scanned,
+            format: metadata.format,
+            compression: metadata.compression,
+            exec_plan: None,
+        };
+        let chunks = metadata.chunks.into_iter().map(PathBuf::from).collect();
+        Ok((result, chunks))
+    }
+
+    fn read_metadata(&self, key: &str) -> Result<(Metadata, u64)> {
+        let metadata = serde_json::from_slice(&std::fs::read(self.metadata_path(key))?)?;
+        let size = std::fs::metadata(self.data_path(key))?.len();
+        Ok((metadata, size))
+    }
+
+    fn evict(&self, keys: Vec<String>) {
+        for key in keys {
+            self.remove_files(&key);
+        }
+    }
+
+    fn remove_files(&self, key: &str) {
+        let _ = std::fs::remove_file(self.metadata_path(key));
+        let _ = std::fs::remove_file(self.data_path(key));
+    }
+
+    fn metadata_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    fn data_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.data"))
+    }
+}
+
+/// Least recently used entries bounded by their total size
+struct Lru<V> {
+    entries: HashMap<String, LruEntry<V>>,
+    by_usage: BTreeMap<u64, String>,
+    size: u64,
+    max_size: u64,
+    tick: u64,
+}
+
+struct LruEntry<V> {
+    last_used: u64,
+    size: u64,
+    chunks: Vec<PathBuf>,
+    value: V,
+}
+
+impl<V> Lru<V> {
+    fn new(max_size: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_usage: BTreeMap::new(),
+            size: 0,
+            max_size,
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.by_usage.remove(&entry.last_used);
+        self.by_usage.insert(self.tick, key.to_owned());
+        entry.last_used = self.tick;
+        Some(&entry.value)
+    }
+
+    /// Returns the keys of the evicted entries.
+    /// Entries larger than the whole cache are not inserted.
+    fn insert(&mut self, key: String, size: u64, chunks: Vec<PathBuf>, value: V) -> Vec<String> {
+        self.remove(&key);
+        if size > self.max_size {
+            return vec![key];
+        }
+        let mut evicted = Vec::new();
+        while self.size + size > self.max_size {
+            let (_, oldest) = self
+                .by_usage
+                .pop_first()
+                .expect("Cache size should match the entries");
+            self.size -= self.entries.remove(&oldest).map_or(0, |entry| entry.size);
+            evicted.push(oldest);
+        }
+        self.tick += 1;
+        self.by_usage.insert(self.tick, key.clone());
+        self.size += size;
+        self.entries.insert(
+            key,
+            LruEntry {
+                last_used: self.tick,
+                size,
+                chunks,
+                value,
+            },
+        );
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.by_usage.remove(&entry.last_used);
+        self.size -= entry.size;
+        Some(entry.value)
+    }
+
+    fn remove_by_chunk(&mut self, chunk: &Path) -> Vec<String> {
+        let keys: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.chunks.iter().any(|c| c == chunk))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            self.remove(key);
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use camino::Utf8PathBuf as PathBuf;
+
+    use super::{cache_key, Lru, ResultCache};
+    use crate::{
+        config::ResultCacheConfig,
+        query::{eth::BatchRequest, result::QueryResult},
+    };
+
+    fn query_result(data: &[u8]) -> QueryResult {
+        QueryResult {
+            data: data.to_vec().into(),
+            data_size: data.len(),
+            compressed_size: data.len(),
+            data_sha3_256: vec![1, 2, 3],
+            num_read_chunks: 1,
+            bytes_scanned: 100,
+            format: Default::default(),
+            compression: Default::default(),
+            exec_plan: None,
+        }
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(10);
+        let chunk = PathBuf::from("ds/0000000000/0000000000-0000000099-hash");
+        assert!(lru.insert("a".to_owned(), 4, vec![], 1).is_empty());
+        assert!(lru
+            .insert("b".to_owned(), 4, vec![chunk.clone()], 2)
+            .is_empty());
+        assert_eq!(lru.get("a"), Some(&1));
+        assert_eq!(lru.insert("c".to_owned(), 4, vec![], 3), ["b"]);
+        assert_eq!(lru.insert("d".to_owned(), 11, vec![], 4), ["d"]);
+        assert_eq!(lru.get("b"), None);
+
+        lru.insert("b".to_owned(), 2, vec![chunk.clone()], 2);
+        assert_eq!(lru.remove_by_chunk(&chunk), ["b"]);
+        assert_eq!(lru.size, 8);
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache() {
+        let dir = PathBuf::try_from(std::env::temp_dir())
+            .unwrap()
+            .join(format!("result-cache-test-{}", std::process::id()));
+        let config = ResultCacheConfig {
+            memory_mb: 1,
+            disk_mb: 1,
+        };
+        let chunk = PathBuf::from("ds/0000000000/0000000000-0000000099-hash");
+
+        let cache = ResultCache::new(&config, dir.clone()).unwrap();
+        cache
+            .insert(
+                "key".to_owned(),
+                vec![chunk.clone()],
+                &query_result(b"data"),
+            )
+            .await;
+        drop(cache);
+
+        let cache = ResultCache::new(&config, dir.clone()).unwrap();
+        let result = cache.get("key").await.unwrap();
+        assert_eq!(result.data, b"data"[..]);
+        assert_eq!(result.data_sha3_256, [1, 2, 3]);
+        // Cached in memory now, sharing the data
+        let again = cache.get("key").await.unwrap();
+        assert_eq!(again.data.as_ptr(), result.data.as_ptr());
+
+        cache.invalidate_chunk(&chunk);
+        assert!(cache.get("key").await.is_none());
+        // The files are removed in the background
+        for _ in 0..100 {
+            if !dir.join("key.json").exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let cache = ResultCache::new(&config, dir.clone()).unwrap();
+        assert!(cache.get("key").await.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_key_normalization() {
+        let key = |query: serde_json::Value| {
+            let query: BatchRequest = serde_json::from_value(query).unwrap();
+            cache_key(&query, "ds", &[], &Default::default())
+        };
+        let expected = key(serde_json::json!({
+            "fromBlock": 1,
+            "fields": {"log": {"address": true, "data": true}},
+            "logs": [{"address": ["0xab", "0xcd"], "topic0": ["0x01"]}],
+            "transactions": [{"sighash": ["0x12345678"]}],
+        }));
+        assert_eq!(
+            key(serde_json::json!({
+                "fields": {"log": {"address": true, "data": true}},
+                "transactions": [{"sighash": ["0x12345678", "0x12345678"]}],
+                "logs": [{"topic0": ["0x01"], "address": ["0xCD", "0xAb"]}],
+                "fromBlock": 1,
+            })),
+            expected
+        );
+        // The fields order changes the result
+        assert_ne!(
+            key(serde_json::json!({
+                "fromBlock": 1,
+                "fields": {"log": {"data": true, "address": true}},
+                "logs": [{"address": ["0xab", "0xcd"], "topic0": ["0x01"]}],
+                "transactions": [{"sighash": ["0x12345678"]}],
+            })),
+            expected
+        );
+        assert_ne!(
+            key(serde_json::json!({
+                "fromBlock": 1,
+                "fields": {"log": {"address": true}},
+                "logs": [{"address": ["0xab", "0xcd"], "topic0": ["0x01"]}],
+                "transactions": [{"sighash": ["0x12345678"]}],
+            })),
+            expected
+        );
+    }
+}
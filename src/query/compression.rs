@@ -0,0 +1,142 @@
+use std::io::Write;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    #[default]
+    Gzip,
+    Zstd,
+    Brotli,
+}
+
+impl Compression {
+    /// Picks the preferred supported codec from the `Accept-Encoding` header.
+    /// Between equally weighted codecs zstd is preferred over brotli and gzip.
+    pub fn from_accept_encoding(header: &str) -> Self {
+        header
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let codec = match parts.next().unwrap_or_default().trim() {
+                    "zstd" | "*" => Self::Zstd,
+                    "br" => Self::Brotli,
+                    "gzip" => Self::Gzip,
+                    _ => return None,
+                };
+                let weight = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+                (weight > 0.0).then_some((codec, weight))
+            })
+            .max_by(|(a, a_weight), (b, b_weight)| {
+                a_weight
+                    .total_cmp(b_weight)
+                    .then(b.preference().cmp(&a.preference()))
+            })
+            .map_or(Self::None, |(codec, _)| codec)
+    }
+
+    /// Value of the `Content-Encoding` header
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gzip"),
+            Self::Zstd => Some("zstd"),
+            Self::Brotli => Some("br"),
+        }
+    }
+
+    /// Compresses the data with the given level clamped to the codec's range
+    /// or the codec's default level
+    pub fn compress(&self, data: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
+        let result = match self {
+            Self::None => data.to_vec(),
+            Self::Gzip => {
+                let level = level.map_or(flate2::Compression::default(), |level| {
+                    flate2::Compression::new(level.min(9))
+                });
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            Self::Zstd => {
+                let level = level.map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |level| {
+                    level.clamp(1, 22) as i32
+                });
+                zstd::bulk::compress(data, level)?
+            }
+            Self::Brotli => {
+                let level = level.map_or(5, |level| level.min(11));
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, level, 22);
+                encoder.write_all(data)?;
+                encoder.into_inner()
+            }
+        };
+        Ok(result)
+    }
+
+    fn preference(&self) -> u8 {
+        match self {
+            Self::Zstd => 0,
+            Self::Brotli => 1,
+            Self::Gzip => 2,
+            Self::None => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::Compression;
+
+    #[test]
+    fn test_from_accept_encoding() {
+        assert_eq!(
+            Compression::from_accept_encoding("gzip, deflate, br, zstd"),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::from_accept_encoding("gzip;q=1.0, zstd;q=0.5"),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_accept_encoding("zstd;q=0, gzip"),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_accept_encoding("identity"),
+            Compression::None
+        );
+        assert_eq!(Compression::from_accept_encoding(""), Compression::None);
+    }
+
+    #[test]
+    fn test_compress() {
+        let data = br#"[{"header":{"number":1}}]"#.repeat(100);
+
+        let compressed = Compression::Gzip.compress(&data, Some(100)).unwrap();
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        let compressed = Compression::Zstd.compress(&data, None).unwrap();
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), data);
+
+        let compressed = Compression::Brotli.compress(&data, Some(3)).unwrap();
+        let mut decompressed = Vec::new();
+        brotli::Decompressor::new(compressed.as_slice(), 4096)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        assert_eq!(Compression::None.compress(&data, None).unwrap(), data);
+    }
+}
@@ -2,98 +2,36 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use camino::Utf8Path as Path;
-use datafusion::arrow::datatypes::{self, DataType, Field, Schema};
-use datafusion::common::{Constraint, Constraints};
+use datafusion::arrow::datatypes::Schema;
+use datafusion::common::{Column, Constraint, Constraints};
 use datafusion::datasource::listing::{ListingTable, ListingTableConfig, ListingTableUrl};
 use datafusion::execution::context::SessionContext;
 use datafusion::execution::options::{ParquetReadOptions, ReadOptions};
+use datafusion::prelude::col;
 use datafusion::sql::TableReference;
-use lazy_static::lazy_static;
 
-lazy_static! {
-    static ref BLOCKS_SCHEMA: Schema = Schema::new(vec![
-        Field::new("number", DataType::Int32, false),
-        Field::new("hash", DataType::Utf8, false),
-        Field::new("parent_hash", DataType::Utf8, true),
-        Field::new("nonce", DataType::Utf8, true),
-        Field::new("sha3_uncles", DataType::Utf8, true),
-        Field::new("logs_bloom", DataType::Utf8, true),
-        Field::new("transactions_root", DataType::Utf8, true),
-        Field::new("state_root", DataType::Utf8, true),
-        Field::new("receipts_root", DataType::Utf8, true),
-        Field::new("mix_hash", DataType::Utf8, true),
-        Field::new("miner", DataType::Utf8, true),
-        Field::new("difficulty", DataType::Utf8, true),
-        Field::new("total_difficulty", DataType::Utf8, true),
-        Field::new("extra_data", DataType::Utf8, true),
-        Field::new("size", DataType::Int32, true),
-        Field::new("gas_limit", DataType::Utf8, true),
-        Field::new("gas_used", DataType::Utf8, true),
-        Field::new(
-            "timestamp",
-            DataType::Timestamp(datatypes::TimeUnit::Millisecond, None),
-            true
-        ),
-        Field::new("base_fee_per_gas", DataType::Utf8, true),
-        Field::new("extra_data_size", DataType::Int64, true),
-    ]);
-    static ref TRANSACTIONS_SCHEMA: Schema = Schema::new(vec![
-        Field::new("block_number", DataType::Int32, false),
-        Field::new("transaction_index", DataType::Int32, false),
-        Field::new("from", DataType::Utf8, true),
-        Field::new("gas", DataType::Utf8, true),
-        Field::new("gas_price", DataType::Utf8, true),
-        Field::new("max_fee_per_gas", DataType::Utf8, true),
-        Field::new("max_priority_fee_per_gas", DataType::Utf8, true),
-        Field::new("hash", DataType::Utf8, true),
-        Field::new("input", DataType::Utf8, true),
-        Field::new("nonce", DataType::Int64, true),
-        Field::new("to", DataType::Utf8, true),
-        Field::new("value", DataType::Utf8, true),
-        Field::new("v", DataType::Utf8, true),
-        Field::new("r", DataType::Utf8, true),
-        Field::new("s", DataType::Utf8, true),
-        Field::new("y_parity", DataType::Int8, true),
-        Field::new("chain_id", DataType::UInt64, true),
-        Field::new("sighash", DataType::Utf8, true),
-        Field::new("gas_used", DataType::Utf8, true),
-        Field::new("cumulative_gas_used", DataType::Utf8, true),
-        Field::new("effective_gas_price", DataType::Utf8, true),
-        Field::new("contract_address", DataType::Utf8, true),
-        Field::new("type", DataType::UInt8, true),
-        Field::new("status", DataType::Int8, true),
-        Field::new("input_size", DataType::UInt64, true),
-        Field::new("_idx", DataType::Int32, true),
-    ]);
-    static ref LOGS_SCHEMA: Schema = Schema::new(vec![
-        Field::new("block_number", DataType::Int32, false),
-        Field::new("transaction_index", DataType::Int32, false),
-        Field::new("log_index", DataType::Int32, false),
-        Field::new("transaction_hash", DataType::Utf8, true),
-        Field::new("address", DataType::Utf8, true),
-        Field::new("data", DataType::Utf8, true),
-        Field::new("topic0", DataType::Utf8, true),
-        Field::new("topic1", DataType::Utf8, true),
-        Field::new("topic2", DataType::Utf8, true),
-        Field::new("topic3", DataType::Utf8, true),
-        Field::new("data_size", DataType::UInt64, true),
-        Field::new("_idx", DataType::Int32, true),
-    ]);
-}
+use super::schema::DatasetSchema;
 
 // Allows setting primary key unlike `SessionContext::register_parquet`.
-// It doesn't affect performance right now but it may change in the future.
+// The files are stored sorted by the primary key,
+// so the declared order lets DataFusion skip sorting by the key.
 fn register_parquet(
     ctx: &SessionContext,
     name: &str,
-    table_path: impl AsRef<Path>,
+    table_path: &Path,
     schema: &Schema,
     pk_indices: Vec<usize>,
 ) -> Result<()> {
     let options = ParquetReadOptions::default().schema(schema);
+    let sort_order = pk_indices
+        .iter()
+        .map(|&i| col(Column::from_name(schema.field(i).name())).sort(true, true))
+        .collect();
+    let listing_options = options
+        .to_listing_options(&ctx.copied_config())
+        .with_file_sort_order(vec![sort_order]);
     let constraints = Constraints::new_unverified(vec![Constraint::PrimaryKey(pk_indices)]);
-    let listing_options = options.to_listing_options(&ctx.copied_config());
-    let table_url = ListingTableUrl::parse(table_path.as_ref())?;
+    let table_url = ListingTableUrl::parse(table_path)?;
     let schema = Arc::new(schema.to_owned());
     let config = ListingTableConfig::new(table_url)
         .with_listing_options(listing_options)
@@ -108,26 +46,21 @@ fn register_parquet(
 
 pub async fn prepare_query_context(path: &Path) -> anyhow::Result<SessionContext> {
     let ctx = SessionContext::new();
-    register_parquet(
-        &ctx,
-        "blocks",
-        path.join("blocks.parquet"),
-        &BLOCKS_SCHEMA,
-        vec![0],
-    )?;
-    register_parquet(
-        &ctx,
-        "transactions",
-        path.join("transactions.parquet"),
-        &TRANSACTIONS_SCHEMA,
-        vec![0, 1],
-    )?;
-    register_parquet(
-        &ctx,
-        "logs",
-        path.join("logs.parquet"),
-        &LOGS_SCHEMA,
-        vec![0, 1, 2],
-    )?;
+    register_chunk(&ctx, path).await?;
     Ok(ctx)
 }
+
+/// Registers the tables listed in the chunk's schema descriptor and returns the descriptor
+pub async fn register_chunk(ctx: &SessionContext, path: &Path) -> anyhow::Result<DatasetSchema> {
+    let dataset_schema = DatasetSchema::load(path)?;
+    for table in &dataset_schema.tables {
+        register_parquet(
+            ctx,
+            &table.name,
+            &path.join(&table.file),
+            &table.schema,
+            table.primary_key.clone(),
+        )?;
+    }
+    Ok(dataset_schema)
+}
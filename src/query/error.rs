@@ -1,4 +1,6 @@
 use axum::{http::StatusCode, response::IntoResponse};
+use datafusion::error::DataFusionError;
+use tracing::warn;
 
 #[derive(thiserror::Error, Debug)]
 pub enum QueryError {
@@ -10,13 +12,56 @@ pub enum QueryError {
     BadRequest(String),
     #[error("Service overloaded")]
     ServiceOverloaded,
+    #[error("Query execution timed out")]
+    Timeout,
+    #[error("Worker is not serving queries: {0}")]
+    NotServing(String),
+    #[error("Query exceeded the memory limit: {0}")]
+    MemoryLimitExceeded(String),
     #[error("Internal error")]
-    Other(#[from] anyhow::Error),
+    Other(anyhow::Error),
 }
 
-impl From<datafusion::error::DataFusionError> for QueryError {
-    fn from(value: datafusion::error::DataFusionError) -> Self {
-        Self::Other(value.context("DataFusion error").into())
+/// Returned by the per-query memory pool when the query itself uses too much memory,
+/// as opposed to the shared pool being exhausted by all the running queries
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+pub struct QueryMemoryLimitExceeded(pub String);
+
+impl QueryError {
+    fn from_memory_error(value: &DataFusionError) -> Option<Self> {
+        match value.find_root() {
+            DataFusionError::External(e) => e
+                .downcast_ref::<QueryMemoryLimitExceeded>()
+                .map(|e| Self::MemoryLimitExceeded(e.0.clone())),
+            DataFusionError::ResourcesExhausted(e) => {
+                warn!("Query memory pool exhausted: {e}");
+                Some(Self::ServiceOverloaded)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<DataFusionError> for QueryError {
+    fn from(value: DataFusionError) -> Self {
+        match Self::from_memory_error(&value) {
+            Some(e) => e,
+            None => Self::Other(value.context("DataFusion error").into()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for QueryError {
+    fn from(value: anyhow::Error) -> Self {
+        // DataFusion errors may also come wrapped, e.g. from the result streams
+        let memory_error = value
+            .chain()
+            .find_map(|e| Self::from_memory_error(e.downcast_ref::<DataFusionError>()?));
+        match memory_error {
+            Some(e) => e,
+            None => Self::Other(value),
+        }
     }
 }
 
@@ -31,6 +76,13 @@ impl IntoResponse for QueryError {
             s @ Self::ServiceOverloaded => {
                 (StatusCode::SERVICE_UNAVAILABLE, s.to_string()).into_response()
             }
+            s @ Self::NotServing(_) => {
+                (StatusCode::SERVICE_UNAVAILABLE, s.to_string()).into_response()
+            }
+            s @ Self::Timeout => (StatusCode::GATEWAY_TIMEOUT, s.to_string()).into_response(),
+            s @ Self::MemoryLimitExceeded(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, s.to_string()).into_response()
+            }
             Self::Other(err) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Couldn't execute query: {:?}", err),
@@ -3,17 +3,28 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{map::Map as JsonMap, Number};
 
+use super::{compression::Compression, format::ResultFormat};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct BatchRequest {
+    #[serde(default)]
     pub from_block: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub to_block: Option<u64>,
+    /// Unix time in seconds. Narrows the block range to the blocks produced at or after it.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub from_timestamp: Option<u64>,
+    /// Unix time in seconds. Narrows the block range to the blocks produced at or before it.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub to_timestamp: Option<u64>,
     #[serde(skip_serializing_if = "std::ops::Not::not", default)]
     pub include_all_blocks: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub fields: Option<FieldSelection>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<Vec<BlockRequest>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub logs: Option<Vec<LogRequest>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub transactions: Option<Vec<TxRequest>>,
@@ -21,6 +32,23 @@ pub struct BatchRequest {
     pub traces: Option<Vec<TraceRequest>>,
     #[serde(default)]
     pub r#type: NetworkType,
+    /// Result encoding. In the HTTP API it can also be chosen with the `Accept` header.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub format: Option<ResultFormat>,
+    /// Compression of the P2P result. The HTTP API uses the `Accept-Encoding` header instead.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub compression: Option<Compression>,
+    /// Clamped to the range supported by the codec
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub compression_level: Option<u32>,
+    /// Return the executed physical plan with per-operator metrics along with the result.
+    /// The HTTP API returns a JSON `{ "data", "execPlan" }` object for such queries.
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub profile: bool,
+    /// Compute units to reserve before the execution, clamped by the worker's cost model.
+    /// The actual cost is charged once the query is executed.
+    #[serde(skip_serializing, default)]
+    pub cu_estimate: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,6 +63,18 @@ pub struct FieldSelection {
     pub trace: Vec<String>,
 }
 
+/// Selects block headers matching all the given conditions
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all(deserialize = "camelCase"), default)]
+pub struct BlockRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub miner: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct LogRequest {
@@ -48,6 +88,9 @@ pub struct LogRequest {
     pub topic2: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub topic3: Option<Vec<String>>,
+    /// Match 20-byte addresses in the topic filters against indexed address parameters
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub address_topics: bool,
     #[serde(skip_serializing_if = "std::ops::Not::not", default)]
     pub transaction: bool,
     #[serde(skip_serializing_if = "std::ops::Not::not", default)]
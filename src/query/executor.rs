@@ -0,0 +1,222 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use datafusion::{
+    error::DataFusionError,
+    execution::{
+        memory_pool::{MemoryConsumer, MemoryPool, MemoryReservation},
+        runtime_env::{RuntimeConfig, RuntimeEnv},
+    },
+    prelude::{SessionConfig, SessionContext},
+};
+use tokio::{runtime::Runtime, task::JoinHandle};
+
+use crate::{config::QueriesConfig, query::error::QueryMemoryLimitExceeded};
+
+/// Runs queries on a separate runtime, so that CPU-heavy query processing
+/// doesn't delay networking and downloads on the main runtime
+pub struct QueryExecutor {
+    // Only taken out on drop
+    runtime: Option<Runtime>,
+    runtime_env: Arc<RuntimeEnv>,
+    threads: usize,
+    query_memory_limit: Option<usize>,
+}
+
+/// Limits the memory used by a single query, while reserving it from the shared pool
+#[derive(Debug)]
+struct QueryMemoryPool {
+    shared: Arc<dyn MemoryPool>,
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl QueryExecutor {
+    pub fn new(config: &QueriesConfig) -> Result<Self> {
+        let threads = match config.threads {
+            Some(threads) => threads,
+            None => std::thread::available_parallelism()?.get(),
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .thread_name("query-executor")
+            .enable_all()
+            .build()?;
+        let mut runtime_config = RuntimeConfig::new();
+        if let Some(limit) = config.memory_limit_mb {
+            // The pool is shared by all the queries
+            runtime_config = runtime_config.with_memory_limit((limit << 20) as usize, 1.0);
+        }
+        Ok(Self {
+            runtime: Some(runtime),
+            runtime_env: Arc::new(RuntimeEnv::new(runtime_config)?),
+            threads,
+            query_memory_limit: config
+                .query_memory_limit_mb
+                .map(|limit| (limit << 20) as usize),
+        })
+    }
+
+    /// Tasks spawned by the future, e.g. by DataFusion, also run on the query runtime
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.runtime
+            .as_ref()
+            .expect("Runtime is only taken on drop")
+            .spawn(future)
+    }
+
+    /// Creates a context for a single query using the executor's memory pool
+    pub fn session_context(&self) -> SessionContext {
+        let config = SessionConfig::new().with_target_partitions(self.threads);
+        let runtime_env = match self.query_memory_limit {
+            Some(limit) => Arc::new(RuntimeEnv {
+                memory_pool: Arc::new(QueryMemoryPool::new(
+                    self.runtime_env.memory_pool.clone(),
+                    limit,
+                )),
+                disk_manager: self.runtime_env.disk_manager.clone(),
+                cache_manager: self.runtime_env.cache_manager.clone(),
+                object_store_registry: self.runtime_env.object_store_registry.clone(),
+            }),
+            None => self.runtime_env.clone(),
+        };
+        SessionContext::new_with_config_rt(config, runtime_env)
+    }
+}
+
+impl QueryMemoryPool {
+    fn new(shared: Arc<dyn MemoryPool>, limit: usize) -> Self {
+        Self {
+            shared,
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl MemoryPool for QueryMemoryPool {
+    fn register(&self, consumer: &MemoryConsumer) {
+        self.shared.register(consumer)
+    }
+
+    fn unregister(&self, consumer: &MemoryConsumer) {
+        self.shared.unregister(consumer)
+    }
+
+    fn grow(&self, reservation: &MemoryReservation, additional: usize) {
+        self.shared.grow(reservation, additional);
+        self.used.fetch_add(additional, Ordering::Relaxed);
+    }
+
+    fn shrink(&self, reservation: &MemoryReservation, shrink: usize) {
+        self.shared.shrink(reservation, shrink);
+        self.used.fetch_sub(shrink, Ordering::Relaxed);
+    }
+
+    fn try_grow(
+        &self,
+        reservation: &MemoryReservation,
+        additional: usize,
+    ) -> datafusion::error::Result<()> {
+        let used = self.used.fetch_add(additional, Ordering::Relaxed);
+        if used + additional > self.limit {
+            self.used.fetch_sub(additional, Ordering::Relaxed);
+            let message = format!(
+                "{} couldn't allocate {additional} bytes, the query already uses {used} of {} bytes",
+                reservation.consumer().name(),
+                self.limit
+            );
+            return Err(DataFusionError::External(Box::new(
+                QueryMemoryLimitExceeded(message),
+            )));
+        }
+        let result = self.shared.try_grow(reservation, additional);
+        if result.is_err() {
+            self.used.fetch_sub(additional, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn reserved(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for QueryExecutor {
+    fn drop(&mut self) {
+        // Dropping a runtime from an async context would panic
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::execution::memory_pool::{GreedyMemoryPool, MemoryConsumer, MemoryPool};
+
+    use super::{QueryExecutor, QueryMemoryPool};
+    use crate::{config::QueriesConfig, query::error::QueryError};
+
+    #[test]
+    fn test_memory_pool() {
+        let shared: Arc<dyn MemoryPool> = Arc::new(GreedyMemoryPool::new(100));
+        let first: Arc<dyn MemoryPool> = Arc::new(QueryMemoryPool::new(shared.clone(), 60));
+        let second: Arc<dyn MemoryPool> = Arc::new(QueryMemoryPool::new(shared.clone(), 60));
+
+        let mut reservation = MemoryConsumer::new("first").register(&first);
+        reservation.try_grow(50).unwrap();
+        let err = reservation.try_grow(20).unwrap_err();
+        assert!(matches!(
+            QueryError::from(err),
+            QueryError::MemoryLimitExceeded(_)
+        ));
+        assert_eq!(first.reserved(), 50);
+
+        let mut other = MemoryConsumer::new("second").register(&second);
+        other.try_grow(50).unwrap();
+        // The shared pool is exhausted
+        let err = other.try_grow(10).unwrap_err();
+        assert!(matches!(
+            QueryError::from(err),
+            QueryError::ServiceOverloaded
+        ));
+        assert_eq!(second.reserved(), 50);
+
+        drop(reservation);
+        assert_eq!(first.reserved(), 0);
+        other.try_grow(10).unwrap();
+        assert_eq!(shared.reserved(), 60);
+    }
+
+    #[tokio::test]
+    async fn test_spawn() {
+        let config = QueriesConfig {
+            threads: Some(2),
+            ..Default::default()
+        };
+        let executor = QueryExecutor::new(&config).unwrap();
+        let main_thread = std::thread::current().id();
+        let query_thread = executor
+            .spawn(async { std::thread::current().id() })
+            .await
+            .unwrap();
+        assert_ne!(main_thread, query_thread);
+
+        let ctx = executor.session_context();
+        let batches = ctx.sql("SELECT 1").await.unwrap().collect().await.unwrap();
+        assert_eq!(batches[0].num_rows(), 1);
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::Write;
+
+use anyhow::Result;
+use datafusion::{
+    arrow::{datatypes::SchemaRef, ipc::writer::StreamWriter, record_batch::RecordBatch},
+    parquet::arrow::ArrowWriter,
+};
+use serde::{Deserialize, Serialize};
+
+// The binary results hold several tables framed as described in [`encode_tables`],
+// so they are not valid Arrow streams or Parquet files and have their own media types
+pub const ARROW_IPC_CONTENT_TYPE: &str = "application/vnd.sqd.tables+arrow-stream";
+pub const PARQUET_CONTENT_TYPE: &str = "application/vnd.sqd.tables+parquet";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultFormat {
+    /// Array of blocks with nested transactions and logs
+    #[default]
+    Json,
+    /// Blocks, transactions and logs tables, each encoded as an Arrow IPC stream,
+    /// served as `application/vnd.sqd.tables+arrow-stream`
+    ArrowIpc,
+    /// Blocks, transactions and logs tables, each encoded as a Parquet file,
+    /// served as `application/vnd.sqd.tables+parquet`
+    Parquet,
+}
+
+impl ResultFormat {
+    /// Picks a binary format from the `Accept` header. JSON is the default for anything else.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        accept.split(',').find_map(|media_type| {
+            match media_type.split(';').next().unwrap_or_default().trim() {
+                ARROW_IPC_CONTENT_TYPE => Some(Self::ArrowIpc),
+                PARQUET_CONTENT_TYPE => Some(Self::Parquet),
+                "application/json" => Some(Self::Json),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::ArrowIpc => ARROW_IPC_CONTENT_TYPE,
+            Self::Parquet => PARQUET_CONTENT_TYPE,
+        }
+    }
+}
+
+pub struct Table {
+    pub name: &'static str,
+    pub schema: SchemaRef,
+    pub batches: Vec<RecordBatch>,
+}
+
+/// Encodes the tables one after another. Each table is framed as
+/// `u32 LE` name length, the UTF-8 name, `u64 LE` payload length and the payload:
+/// a complete Arrow IPC stream or Parquet file that can be read on its own.
+/// The blocks table comes first, followed by the requested transactions and logs tables.
+pub fn encode_tables(tables: &[Table], format: ResultFormat) -> Result<Vec<u8>> {
+    let mut result = Vec::new();
+    for table in tables {
+        let payload = match format {
+            ResultFormat::ArrowIpc => encode_arrow_ipc(table)?,
+            ResultFormat::Parquet => encode_parquet(table)?,
+            ResultFormat::Json => anyhow::bail!("JSON results are not built from tables"),
+        };
+        result.write_all(&(table.name.len() as u32).to_le_bytes())?;
+        result.write_all(table.name.as_bytes())?;
+        result.write_all(&(payload.len() as u64).to_le_bytes())?;
+        result.write_all(&payload)?;
+    }
+    Ok(result)
+}
+
+fn encode_arrow_ipc(table: &Table) -> Result<Vec<u8>> {
+    let mut writer = StreamWriter::try_new(Vec::new(), &table.schema)?;
+    for batch in &table.batches {
+        writer.write(batch)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+fn encode_parquet(table: &Table) -> Result<Vec<u8>> {
+    let mut writer = ArrowWriter::try_new(Vec::new(), table.schema.clone(), None)?;
+    for batch in &table.batches {
+        writer.write(batch)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::{
+        array::{Int32Array, StringArray},
+        datatypes::{DataType, Field, Schema},
+        ipc::reader::StreamReader,
+        record_batch::RecordBatch,
+    };
+
+    use super::{encode_tables, ResultFormat, Table};
+
+    fn split_frames(mut data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let name_len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+            let name = String::from_utf8(data[4..4 + name_len].to_vec()).unwrap();
+            data = &data[4 + name_len..];
+            let len = u64::from_le_bytes(data[..8].try_into().unwrap()) as usize;
+            frames.push((name, data[8..8 + len].to_vec()));
+            data = &data[8 + len..];
+        }
+        frames
+    }
+
+    #[test]
+    fn test_from_accept() {
+        assert_eq!(
+            ResultFormat::from_accept("application/vnd.sqd.tables+arrow-stream"),
+            Some(ResultFormat::ArrowIpc)
+        );
+        assert_eq!(
+            ResultFormat::from_accept("text/html, application/vnd.sqd.tables+parquet;q=0.9"),
+            Some(ResultFormat::Parquet)
+        );
+        // A single table can't be returned as a plain Arrow stream
+        assert_eq!(
+            ResultFormat::from_accept("application/vnd.apache.arrow.stream"),
+            None
+        );
+        assert_eq!(ResultFormat::from_accept("*/*"), None);
+    }
+
+    #[test]
+    fn test_encode_arrow_ipc() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("number", DataType::Int32, false),
+            Field::new("hash", DataType::Utf8, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2])),
+                Arc::new(StringArray::from(vec!["0x01", "0x02"])),
+            ],
+        )
+        .unwrap();
+        let tables = [
+            Table {
+                name: "blocks",
+                schema: schema.clone(),
+                batches: vec![batch.clone()],
+            },
+            Table {
+                name: "logs",
+                schema,
+                batches: vec![],
+            },
+        ];
+
+        let data = encode_tables(&tables, ResultFormat::ArrowIpc).unwrap();
+        let frames = split_frames(&data);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0, "blocks");
+        let batches: Vec<_> = StreamReader::try_new(frames[0].1.as_slice(), None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches, vec![batch]);
+        assert_eq!(frames[1].0, "logs");
+        let reader = StreamReader::try_new(frames[1].1.as_slice(), None).unwrap();
+        assert_eq!(reader.count(), 0);
+
+        assert!(!encode_tables(&tables, ResultFormat::Parquet)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,240 @@
+use datafusion::arrow::{
+    array::{Array, ArrayRef, AsArray, OffsetSizeTrait},
+    datatypes::*,
+    error::ArrowError,
+    record_batch::RecordBatch,
+    util::display::{ArrayFormatter, FormatOptions},
+};
+use serde::Serialize;
+
+/// Writes a non-null value of the column at the given row
+type Encoder = Box<dyn Fn(usize, &mut Vec<u8>) + Send + Sync>;
+
+/// Writes table rows as JSON objects straight from the Arrow arrays.
+/// Like `arrow::json`, it omits null values, but the requested fields
+/// without a value are written as nulls after the other fields.
+pub struct ObjectWriter {
+    /// `"name":` prefix of each column, `None` for the skipped columns
+    keys: Vec<Option<Vec<u8>>>,
+    /// Requested fields with the position of the column providing their value in `columns`
+    null_fields: Vec<(Vec<u8>, Option<usize>)>,
+    /// Encoders of the current batch's columns, skipped columns excluded
+    columns: Vec<(usize, ArrayRef, Encoder)>,
+}
+
+impl ObjectWriter {
+    pub fn new(schema: &Schema, skip: &[&str], requested: &[String]) -> Self {
+        let keys = schema
+            .fields()
+            .iter()
+            .map(|field| (!skip.contains(&field.name().as_str())).then(|| key(field.name())))
+            .collect::<Vec<_>>();
+        let mut null_fields: Vec<(Vec<u8>, Option<usize>)> = Vec::new();
+        for name in requested {
+            let key = key(name);
+            if null_fields.iter().any(|(k, _)| *k == key) {
+                continue;
+            }
+            let column = schema
+                .index_of(name)
+                .ok()
+                .filter(|&i| keys[i].is_some())
+                .map(|i| keys[..i].iter().flatten().count());
+            null_fields.push((key, column));
+        }
+        Self {
+            keys,
+            null_fields,
+            columns: Vec::new(),
+        }
+    }
+
+    /// Prepares the writer for the rows of the next batch
+    pub fn set_batch(&mut self, batch: &RecordBatch) -> Result<(), ArrowError> {
+        self.columns.clear();
+        for (i, column) in batch.columns().iter().enumerate() {
+            if self.keys[i].is_some() {
+                self.columns
+                    .push((i, column.clone(), make_encoder(column)?));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_row(&self, row: usize, out: &mut Vec<u8>) {
+        out.push(b'{');
+        let mut empty = true;
+        let mut write_key = |key: &[u8], out: &mut Vec<u8>| {
+            if !empty {
+                out.push(b',');
+            }
+            empty = false;
+            out.extend_from_slice(key);
+        };
+        for (i, array, encode) in &self.columns {
+            if array.is_valid(row) {
+                write_key(self.keys[*i].as_ref().expect("Column is not skipped"), out);
+                encode(row, out);
+            }
+        }
+        for (key, column) in &self.null_fields {
+            if !column.is_some_and(|c| self.columns[c].1.is_valid(row)) {
+                write_key(key, out);
+                out.extend_from_slice(b"null");
+            }
+        }
+        out.push(b'}');
+    }
+}
+
+fn key(name: &str) -> Vec<u8> {
+    let mut key = Vec::new();
+    write_json(&mut key, name);
+    key.push(b':');
+    key
+}
+
+fn write_json<T: Serialize + ?Sized>(out: &mut Vec<u8>, value: &T) {
+    serde_json::to_writer(out, value).expect("Writing to a vector can't fail")
+}
+
+fn make_encoder(array: &ArrayRef) -> Result<Encoder, ArrowError> {
+    macro_rules! primitive {
+        ($t:ty) => {{
+            let array = array.as_primitive::<$t>().clone();
+            Box::new(move |row: usize, out: &mut Vec<u8>| write_json(out, &array.value(row)))
+        }};
+    }
+    let encoder: Encoder = match array.data_type() {
+        DataType::Boolean => {
+            let array = array.as_boolean().clone();
+            Box::new(move |row: usize, out: &mut Vec<u8>| write_json(out, &array.value(row)))
+        }
+        DataType::Int8 => primitive!(Int8Type),
+        DataType::Int16 => primitive!(Int16Type),
+        DataType::Int32 => primitive!(Int32Type),
+        DataType::Int64 => primitive!(Int64Type),
+        DataType::UInt8 => primitive!(UInt8Type),
+        DataType::UInt16 => primitive!(UInt16Type),
+        DataType::UInt32 => primitive!(UInt32Type),
+        DataType::UInt64 => primitive!(UInt64Type),
+        DataType::Float64 => primitive!(Float64Type),
+        // `arrow::json` writes all floats as f64
+        DataType::Float32 => {
+            let array = array.as_primitive::<Float32Type>().clone();
+            Box::new(move |row: usize, out: &mut Vec<u8>| {
+                write_json(out, &(array.value(row) as f64))
+            })
+        }
+        DataType::Utf8 => string_encoder::<i32>(array),
+        DataType::LargeUtf8 => string_encoder::<i64>(array),
+        DataType::List(_) => list_encoder::<i32>(array)?,
+        DataType::LargeList(_) => list_encoder::<i64>(array)?,
+        _ => {
+            // Other types are written as their display strings
+            let options = FormatOptions::default();
+            ArrayFormatter::try_new(array.as_ref(), &options)?;
+            let array = array.clone();
+            Box::new(move |row: usize, out: &mut Vec<u8>| {
+                let formatter = ArrayFormatter::try_new(array.as_ref(), &options)
+                    .expect("Formatter was created before");
+                write_json(out, &formatter.value(row).to_string())
+            })
+        }
+    };
+    Ok(encoder)
+}
+
+fn string_encoder<O: OffsetSizeTrait>(array: &ArrayRef) -> Encoder {
+    let array = array.as_string::<O>().clone();
+    Box::new(move |row: usize, out: &mut Vec<u8>| write_json(out, array.value(row)))
+}
+
+fn list_encoder<O: OffsetSizeTrait>(array: &ArrayRef) -> Result<Encoder, ArrowError> {
+    let array = array.as_list::<O>().clone();
+    let values = array.values().clone();
+    let encode = make_encoder(&values)?;
+    Ok(Box::new(move |row: usize, out: &mut Vec<u8>| {
+        let offsets = array.value_offsets();
+        out.push(b'[');
+        for i in offsets[row].as_usize()..offsets[row + 1].as_usize() {
+            if i > offsets[row].as_usize() {
+                out.push(b',');
+            }
+            if values.is_valid(i) {
+                encode(i, out);
+            } else {
+                out.extend_from_slice(b"null");
+            }
+        }
+        out.push(b']');
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::{
+        array::{Float32Array, Int32Array, ListBuilder, StringArray, StringBuilder, UInt64Array},
+        datatypes::{DataType, Field, Schema},
+        json::writer::record_batches_to_json_rows,
+        record_batch::RecordBatch,
+    };
+
+    use super::ObjectWriter;
+
+    #[test]
+    fn test_object_writer() {
+        let mut topics = ListBuilder::new(StringBuilder::new());
+        topics.append_value([Some("0x01"), None]);
+        topics.append_null();
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("blockNumber", DataType::UInt64, false),
+                Field::new("logIndex", DataType::Int32, true),
+                Field::new("data", DataType::Utf8, true),
+                Field::new("value", DataType::Float32, true),
+                Field::new(
+                    "topics",
+                    DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+                    true,
+                ),
+            ])),
+            vec![
+                Arc::new(UInt64Array::from(vec![1, 2])),
+                Arc::new(Int32Array::from(vec![Some(0), None])),
+                Arc::new(StringArray::from(vec![Some("\"quoted\"\n"), None])),
+                Arc::new(Float32Array::from(vec![Some(0.1), Some(f32::NAN)])),
+                Arc::new(topics.finish()),
+            ],
+        )
+        .unwrap();
+
+        let requested = ["data", "logIndex", "data", "blockNumber"].map(String::from);
+        let mut writer = ObjectWriter::new(&batch.schema(), &["blockNumber"], &requested);
+        writer.set_batch(&batch).unwrap();
+        let mut out = Vec::new();
+        writer.write_row(0, &mut out);
+        out.push(b'\n');
+        writer.write_row(1, &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"logIndex":0,"data":"\"quoted\"\n","value":0.10000000149011612,"topics":["0x01",null],"blockNumber":null}"#,
+                "\n",
+                r#"{"value":null,"data":null,"logIndex":null,"blockNumber":null}"#,
+            )
+        );
+
+        let mut writer = ObjectWriter::new(&batch.schema(), &[], &[]);
+        writer.set_batch(&batch).unwrap();
+        let mut out = Vec::new();
+        writer.write_row(0, &mut out);
+        let expected = record_batches_to_json_rows(&[&batch]).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&out).unwrap(),
+            serde_json::Value::Object(expected[0].clone())
+        );
+    }
+}
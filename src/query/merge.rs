@@ -0,0 +1,203 @@
+use datafusion::{
+    arrow::{
+        compute::interleave,
+        datatypes::SchemaRef,
+        record_batch::RecordBatch,
+        row::{OwnedRow, RowConverter, Rows, SortField},
+    },
+    error::DataFusionError,
+    physical_plan::{stream::RecordBatchStreamAdapter, SendableRecordBatchStream},
+};
+use futures::{Stream, StreamExt};
+use itertools::Itertools;
+
+/// Merges the streams sorted by the key columns into a single sorted stream.
+/// Only the first row is kept for each key, so the streams may select overlapping rows.
+pub fn merge_dedup(
+    schema: SchemaRef,
+    inputs: Vec<SendableRecordBatchStream>,
+    key_columns: &[String],
+    batch_size: usize,
+) -> Result<SendableRecordBatchStream, DataFusionError> {
+    let key_indices = key_columns
+        .iter()
+        .map(|column| schema.index_of(column))
+        .collect::<Result<Vec<_>, _>>()?;
+    let converter = RowConverter::new(
+        key_indices
+            .iter()
+            .map(|&i| SortField::new(schema.field(i).data_type().clone()))
+            .collect(),
+    )?;
+    let stream = merge_stream(schema.clone(), inputs, key_indices, converter, batch_size);
+    Ok(Box::pin(RecordBatchStreamAdapter::new(schema, stream)))
+}
+
+struct Cursor {
+    stream: SendableRecordBatchStream,
+    /// Index of the current batch in the list of the buffered batches
+    batch: usize,
+    keys: Rows,
+    position: usize,
+    done: bool,
+}
+
+fn merge_stream(
+    schema: SchemaRef,
+    inputs: Vec<SendableRecordBatchStream>,
+    key_indices: Vec<usize>,
+    converter: RowConverter,
+    batch_size: usize,
+) -> impl Stream<Item = Result<RecordBatch, DataFusionError>> {
+    async_stream::try_stream! {
+        let mut cursors = inputs
+            .into_iter()
+            .map(|stream| Cursor {
+                stream,
+                batch: 0,
+                keys: converter.empty_rows(0, 0),
+                position: 0,
+                done: false,
+            })
+            .collect_vec();
+        let mut batches: Vec<RecordBatch> = Vec::new();
+        let mut indices: Vec<(usize, usize)> = Vec::new();
+        let mut last_key: Option<OwnedRow> = None;
+        loop {
+            for cursor in cursors.iter_mut() {
+                while !cursor.done && cursor.position >= cursor.keys.num_rows() {
+                    match cursor.stream.next().await {
+                        Some(batch) => {
+                            let batch = batch?;
+                            let columns = key_indices
+                                .iter()
+                                .map(|&i| batch.column(i).clone())
+                                .collect_vec();
+                            cursor.keys = converter.convert_columns(&columns)?;
+                            cursor.position = 0;
+                            cursor.batch = batches.len();
+                            batches.push(batch);
+                        }
+                        None => cursor.done = true,
+                    }
+                }
+            }
+
+            let next = cursors
+                .iter_mut()
+                .filter(|cursor| !cursor.done)
+                .min_by(|a, b| a.keys.row(a.position).cmp(&b.keys.row(b.position)));
+            let Some(cursor) = next else {
+                break;
+            };
+            let key = cursor.keys.row(cursor.position);
+            if last_key.as_ref().map_or(true, |last| last.row() != key) {
+                indices.push((cursor.batch, cursor.position));
+                last_key = Some(key.owned());
+            }
+            cursor.position += 1;
+
+            if indices.len() >= batch_size {
+                yield interleave_batches(&schema, &batches, &indices)?;
+                indices.clear();
+                // Only the current batches of the cursors may be referenced later
+                let current = cursors.iter().map(|cursor| cursor.batch).unique().collect_vec();
+                for cursor in cursors.iter_mut() {
+                    cursor.batch = current.iter().position(|&b| b == cursor.batch).unwrap();
+                }
+                batches = current.into_iter().map(|b| batches[b].clone()).collect();
+            }
+        }
+        if !indices.is_empty() {
+            yield interleave_batches(&schema, &batches, &indices)?;
+        }
+    }
+}
+
+fn interleave_batches(
+    schema: &SchemaRef,
+    batches: &[RecordBatch],
+    indices: &[(usize, usize)],
+) -> Result<RecordBatch, DataFusionError> {
+    let columns = (0..schema.fields().len())
+        .map(|column| {
+            let arrays = batches
+                .iter()
+                .map(|batch| batch.column(column).as_ref())
+                .collect_vec();
+            interleave(&arrays, indices)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::{
+        arrow::{
+            array::{StringArray, UInt64Array},
+            datatypes::{DataType, Field, Schema, SchemaRef},
+            record_batch::RecordBatch,
+        },
+        physical_plan::{
+            common::collect, stream::RecordBatchStreamAdapter, SendableRecordBatchStream,
+        },
+    };
+
+    use super::merge_dedup;
+
+    fn batch(schema: &SchemaRef, rows: &[(u64, u64, &str)]) -> RecordBatch {
+        RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| r.0))),
+                Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| r.1))),
+                Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.2))),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_merge_dedup() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("blockNumber", DataType::UInt64, false),
+            Field::new("transactionIndex", DataType::UInt64, false),
+            Field::new("hash", DataType::Utf8, true),
+        ]));
+        let input = |batches: Vec<RecordBatch>| -> SendableRecordBatchStream {
+            let stream = futures::stream::iter(batches.into_iter().map(Ok));
+            Box::pin(RecordBatchStreamAdapter::new(schema.clone(), stream))
+        };
+        let first = input(vec![
+            batch(&schema, &[(1, 0, "a"), (1, 2, "c")]),
+            batch(&schema, &[]),
+            batch(&schema, &[(3, 1, "e")]),
+        ]);
+        let second = input(vec![
+            batch(&schema, &[(1, 1, "b"), (1, 2, "c")]),
+            batch(&schema, &[(2, 0, "d"), (3, 1, "e"), (4, 0, "f")]),
+        ]);
+        let key = ["blockNumber".to_owned(), "transactionIndex".to_owned()];
+
+        let merged = merge_dedup(schema.clone(), vec![first, second], &key, 4).unwrap();
+        let batches = collect(merged).await.unwrap();
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>(),
+            [4, 2]
+        );
+        let hashes: Vec<_> = batches
+            .iter()
+            .flat_map(|b| {
+                let column = b.column(2).as_any().downcast_ref::<StringArray>().unwrap();
+                column
+                    .iter()
+                    .map(|v| v.unwrap().to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(hashes, ["a", "b", "c", "d", "e", "f"]);
+    }
+}
@@ -1,7 +1,16 @@
+pub mod cache;
+pub mod compression;
 pub mod context;
 pub mod error;
 pub mod eth;
+pub mod executor;
+pub mod format;
+pub mod json;
+pub mod merge;
 pub mod processor;
+pub mod profile;
 pub mod result;
+pub mod schema;
+pub mod sql;
 #[cfg(test)]
 mod tests;
@@ -1,26 +1,79 @@
-use std::{collections::HashSet, hash::Hash, pin::Pin};
+use std::{collections::HashSet, hash::Hash, sync::Arc};
 
 use super::{
     error::QueryError,
-    eth::{BatchRequest, NetworkType},
+    eth::{BatchRequest, BlockRequest, NetworkType},
+    format::Table,
+    json::ObjectWriter,
+    merge::merge_dedup,
+    profile::ExecPlan,
 };
 use anyhow::Context;
-use async_stream::try_stream;
 use datafusion::{
     arrow::{
-        datatypes::DataType, json::writer::record_batches_to_json_rows, record_batch::RecordBatch,
+        array::{Array, AsArray, BooleanArray, UInt64Array},
+        compute,
+        datatypes::{DataType, SchemaRef, UInt64Type},
+        record_batch::RecordBatch,
     },
+    common::{Column, Constraint},
     error::DataFusionError,
+    execution::TaskContext,
+    physical_plan::{collect, common, execute_stream, ExecutionPlan, SendableRecordBatchStream},
     prelude::*,
     scalar::ScalarValue,
 };
-use futures::{stream::Peekable, try_join, Stream, StreamExt};
+use futures::StreamExt;
 use itertools::Itertools;
-use serde_json::{map::Map as JsonMap, Value};
 use serde_rename_rule::RenameRule;
 use tracing::instrument;
 
-pub(super) type QueryResult = Vec<Value>;
+/// Marks the block headers matching the query's block requests in the JSON output
+const SELECTED_COLUMN: &str = "_selected";
+
+pub struct QueryOutput {
+    /// JSON array of the blocks, see [`write_response`]
+    pub data: Vec<u8>,
+    /// Total number of bytes read from the parquet files
+    pub bytes_scanned: u64,
+    /// Only present if the query requested profiling
+    pub exec_plan: Option<ExecPlan>,
+}
+
+pub struct TablesOutput {
+    pub tables: Vec<Table>,
+    /// Total number of bytes read from the parquet files
+    pub bytes_scanned: u64,
+    /// Only present if the query requested profiling
+    pub exec_plan: Option<ExecPlan>,
+}
+
+/// Subqueries selecting rows of the same table, each sorted by the table's primary key.
+/// A row may be selected by several subqueries.
+struct Selection {
+    plans: Vec<Arc<dyn ExecutionPlan>>,
+    /// Camel-cased primary key columns
+    key: Vec<String>,
+}
+
+impl Selection {
+    /// Merges the subqueries' outputs keeping a single copy of each row
+    fn execute(
+        &self,
+        task_ctx: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream, DataFusionError> {
+        if let [plan] = self.plans.as_slice() {
+            return execute_stream(plan.clone(), task_ctx);
+        }
+        let inputs = self
+            .plans
+            .iter()
+            .map(|plan| execute_stream(plan.clone(), task_ctx.clone()))
+            .collect::<Result<_, _>>()?;
+        let batch_size = task_ctx.session_config().batch_size();
+        merge_dedup(self.plans[0].schema(), inputs, &self.key, batch_size)
+    }
+}
 
 // TODO:
 // - optimize queries
@@ -34,42 +87,218 @@ pub(super) type QueryResult = Vec<Value>;
 pub async fn process_query(
     ctx: &SessionContext,
     query: BatchRequest,
-) -> Result<QueryResult, QueryError> {
+) -> Result<QueryOutput, QueryError> {
+    check_network_type(&query)?;
+    let (blocks_plan, transactions, logs) = extract_data(ctx, &query).await?;
+    let task_ctx = ctx.task_ctx();
+    let execute = |selection: &Selection| selection.execute(task_ctx.clone());
+    let blocks = execute_stream(blocks_plan.clone(), task_ctx.clone())?;
+    let tx_rows = transactions.as_ref().map(execute).transpose()?;
+    let logs_rows = logs.as_ref().map(execute).transpose()?;
+    let data = write_response(&query, blocks, tx_rows, logs_rows).await?;
+
+    Ok(QueryOutput {
+        data,
+        bytes_scanned: total_bytes_scanned(&blocks_plan, &transactions, &logs),
+        exec_plan: query
+            .profile
+            .then(|| exec_plan(&blocks_plan, &transactions, &logs)),
+    })
+}
+
+/// Returns the selected tables as is, skipping the JSON conversion.
+/// The blocks table has the same blocks as the JSON response, see [`select_blocks`].
+#[instrument(skip_all)]
+pub async fn process_query_tables(
+    ctx: &SessionContext,
+    query: BatchRequest,
+) -> Result<TablesOutput, QueryError> {
+    check_network_type(&query)?;
+    let (blocks_plan, transactions, logs) = extract_data(ctx, &query).await?;
+    let task_ctx = ctx.task_ctx();
+    let blocks = collect(blocks_plan.clone(), task_ctx.clone());
+    let selections = [("transactions", &transactions), ("logs", &logs)]
+        .into_iter()
+        .filter_map(|(name, selection)| {
+            let task_ctx = task_ctx.clone();
+            selection.as_ref().map(|selection| async move {
+                let stream = selection.execute(task_ctx)?;
+                let schema = stream.schema();
+                let batches = common::collect(stream).await?;
+                Ok::<_, DataFusionError>(Table {
+                    name,
+                    schema,
+                    batches,
+                })
+            })
+        });
+    let (blocks, selections) =
+        futures::future::try_join(blocks, futures::future::try_join_all(selections)).await?;
+    let blocks = select_blocks(
+        &blocks_plan.schema(),
+        &blocks,
+        &selections,
+        query.include_all_blocks,
+    )?;
+    let tables = std::iter::once(blocks).chain(selections).collect();
+
+    Ok(TablesOutput {
+        tables,
+        bytes_scanned: total_bytes_scanned(&blocks_plan, &transactions, &logs),
+        exec_plan: query
+            .profile
+            .then(|| exec_plan(&blocks_plan, &transactions, &logs)),
+    })
+}
+
+/// Keeps the blocks written by [`write_response`]: the ones matching the block requests
+/// or having rows in the other tables, and the first and the last blocks of the range.
+/// The [`SELECTED_COLUMN`] is dropped.
+fn select_blocks(
+    schema: &SchemaRef,
+    batches: &[RecordBatch],
+    tables: &[Table],
+    include_all_blocks: bool,
+) -> anyhow::Result<Table> {
+    let mut with_rows = HashSet::new();
+    for batch in tables.iter().flat_map(|table| &table.batches) {
+        with_rows.extend(
+            block_numbers(batch, "blockNumber")?
+                .values()
+                .iter()
+                .copied(),
+        );
+    }
+    let columns = (0..schema.fields().len())
+        .filter(|&i| schema.field(i).name() != SELECTED_COLUMN)
+        .collect_vec();
+    let last_batch = batches.iter().rposition(|batch| batch.num_rows() > 0);
+    let mut result = Vec::with_capacity(batches.len());
+    let mut is_first = true;
+    for (i, batch) in batches.iter().enumerate() {
+        let numbers = block_numbers(batch, "number")?;
+        let selected = batch
+            .column_by_name(SELECTED_COLUMN)
+            .map(|column| column.as_boolean().clone());
+        let num_rows = batch.num_rows();
+        let mask: BooleanArray = (0..num_rows)
+            .map(|row| {
+                let keep = include_all_blocks
+                    || (is_first && row == 0)
+                    || (Some(i) == last_batch && row + 1 == num_rows)
+                    || selected
+                        .as_ref()
+                        .is_some_and(|selected| selected.is_valid(row) && selected.value(row))
+                    || with_rows.contains(&numbers.value(row));
+                Some(keep)
+            })
+            .collect();
+        is_first &= num_rows == 0;
+        result.push(compute::filter_record_batch(
+            &batch.project(&columns)?,
+            &mask,
+        )?);
+    }
+    Ok(Table {
+        name: "blocks",
+        schema: Arc::new(schema.project(&columns)?),
+        batches: result,
+    })
+}
+
+fn check_network_type(query: &BatchRequest) -> Result<(), QueryError> {
     if query.r#type != NetworkType::Eth {
         return Err(QueryError::BadRequest(
             "only eth queries are supported".to_owned(),
         ));
     }
-    let (blocks, transactions, logs) = extract_data(ctx, &query).await?;
-    let blocks = convert_to_json(blocks);
-    let transactions = transactions.map(convert_to_json);
-    let logs = logs.map(convert_to_json);
-    collect_result(build_response(&query, blocks, transactions, logs))
-        .await
-        .map_err(From::from)
+    Ok(())
+}
+
+/// Narrows the requested block range to the blocks within the requested time range
+async fn resolve_block_range(
+    ctx: &SessionContext,
+    query: &BatchRequest,
+) -> Result<(u64, Option<u64>), QueryError> {
+    if query.from_timestamp.is_none() && query.to_timestamp.is_none() {
+        return Ok((query.from_block, query.to_block));
+    }
+    let timestamp = |seconds: u64| {
+        lit(ScalarValue::TimestampMillisecond(
+            Some(seconds.saturating_mul(1000) as i64),
+            None,
+        ))
+    };
+    let mut filters = vec![col("number").gt_eq(lit(query.from_block))];
+    filters.extend(
+        query
+            .to_block
+            .map(|to_block| col("number").lt_eq(lit(to_block))),
+    );
+    filters.extend(
+        query
+            .from_timestamp
+            .map(|from| col("timestamp").gt_eq(timestamp(from))),
+    );
+    filters.extend(
+        query
+            .to_timestamp
+            .map(|to| col("timestamp").lt_eq(timestamp(to))),
+    );
+    let batches = ctx
+        .table("blocks")
+        .await?
+        .filter(all_of(filters).expect("Filters are not empty"))?
+        .aggregate(
+            vec![],
+            vec![
+                cast(min(col("number")), DataType::UInt64),
+                cast(max(col("number")), DataType::UInt64),
+            ],
+        )?
+        .collect()
+        .await?;
+    let bound = |column: usize| {
+        batches.first().and_then(|batch| {
+            let array = batch
+                .column(column)
+                .as_any()
+                .downcast_ref::<UInt64Array>()?;
+            (!array.is_null(0)).then(|| array.value(0))
+        })
+    };
+    match (bound(0), bound(1)) {
+        (Some(first), Some(last)) => Ok((first, Some(last))),
+        // No blocks in the time range, so the range is made empty
+        _ => Ok((query.from_block.saturating_add(1), Some(query.from_block))),
+    }
+}
+
+fn block_filter(request: &BlockRequest) -> Expr {
+    let mut filters = Vec::new();
+    filters.extend(field_in("miner", &request.miner));
+    filters.extend(request.min_size.map(|size| col("size").gt_eq(lit(size))));
+    filters.extend(request.max_size.map(|size| col("size").lt_eq(lit(size))));
+    all_of(filters).unwrap_or(lit(true))
 }
 
+/// All blocks of the range are returned, the ones matching the block requests
+/// are marked with the [`SELECTED_COLUMN`].
 #[instrument(skip_all)]
 async fn extract_data(
     ctx: &SessionContext,
     query: &BatchRequest,
-) -> Result<
-    (
-        impl Stream<Item = Result<RecordBatch, DataFusionError>>,
-        Option<impl Stream<Item = Result<RecordBatch, DataFusionError>>>,
-        Option<impl Stream<Item = Result<RecordBatch, DataFusionError>>>,
-    ),
-    QueryError,
-> {
+) -> Result<(Arc<dyn ExecutionPlan>, Option<Selection>, Option<Selection>), QueryError> {
     let blocks = ctx.table("blocks").await?;
     let transactions = ctx.table("transactions").await?;
     let logs = ctx.table("logs").await?;
 
+    let (from_block, to_block) = resolve_block_range(ctx, query).await?;
     let range_filter = |column| {
-        if let Some(to_block) = query.to_block {
-            col(column).between(lit(query.from_block), lit(to_block))
+        if let Some(to_block) = to_block {
+            col(column).between(lit(from_block), lit(to_block))
         } else {
-            col(column).gt_eq(lit(query.from_block))
+            col(column).gt_eq(lit(from_block))
         }
     };
     let all_blocks = blocks
@@ -125,10 +354,11 @@ async fn extract_data(
     for log_request in query.logs.as_ref().unwrap_or(&Vec::new()) {
         let mut filters = Vec::new();
         filters.extend(field_in("address", &log_request.address));
-        filters.extend(field_in("topic0", &log_request.topic0));
-        filters.extend(field_in("topic1", &log_request.topic1));
-        filters.extend(field_in("topic2", &log_request.topic2));
-        filters.extend(field_in("topic3", &log_request.topic3));
+        let address_topics = log_request.address_topics;
+        filters.extend(topic_in("topic0", &log_request.topic0, address_topics));
+        filters.extend(topic_in("topic1", &log_request.topic1, address_topics));
+        filters.extend(topic_in("topic2", &log_request.topic2, address_topics));
+        filters.extend(topic_in("topic3", &log_request.topic3, address_topics));
 
         let predicate = all_of(filters).unwrap_or(lit(true));
         if log_request.transaction {
@@ -148,7 +378,21 @@ async fn extract_data(
         ));
     }
 
-    let blocks = camel_case_columns(all_blocks)?.select_columns(&block_columns(query))?;
+    let blocks_filter = query
+        .blocks
+        .as_ref()
+        .and_then(|requests| any_of(requests.iter().map(block_filter).collect()));
+    let blocks = match blocks_filter {
+        Some(filter) => {
+            let mut columns = block_columns(query);
+            columns.push(SELECTED_COLUMN);
+            select_columns(
+                camel_case_columns(all_blocks)?.with_column(SELECTED_COLUMN, filter)?,
+                &columns,
+            )?
+        }
+        None => select_columns(camel_case_columns(all_blocks)?, &block_columns(query))?,
+    };
 
     let mut tx_selections = Vec::new();
     if let Some(filter) = any_of(tx_filters) {
@@ -164,18 +408,8 @@ async fn extract_data(
             Some(filter),
         )?);
     }
-    let transactions = match union_all(tx_selections)? {
-        Some(union) => {
-            let result = camel_case_columns(union)?
-                .select_columns(&tx_columns(query))?
-                .sort(vec![
-                    col("\"blockNumber\"").sort(true, true),
-                    col("\"transactionIndex\"").sort(true, true),
-                ])?;
-            Some(result)
-        }
-        None => None,
-    };
+    let tx_key = primary_key(ctx, "transactions").await?;
+    let tx_selections = sort_selections(tx_selections, tx_columns(query), &tx_key)?;
 
     let mut logs_selections = Vec::new();
     if let Some(filter) = any_of(logs_filters) {
@@ -191,174 +425,244 @@ async fn extract_data(
             Some(filter),
         )?);
     }
-    let logs = match union_all(logs_selections)? {
-        Some(union) => {
-            let result = camel_case_columns(union)?
-                .select_columns(&log_columns(query))?
-                .sort(vec![
-                    col("\"blockNumber\"").sort(true, true),
-                    col("\"transactionIndex\"").sort(true, true),
-                    col("\"logIndex\"").sort(true, true),
-                ])?;
-            Some(result)
-        }
-        None => None,
-    };
+    let logs_key = primary_key(ctx, "logs").await?;
+    let logs_selections = sort_selections(logs_selections, log_columns(query), &logs_key)?;
 
-    let blocks_future = tokio::spawn(blocks.execute_stream());
-    let tx_future = tokio::spawn(async move {
-        match transactions {
-            Some(transactions) => transactions.execute_stream().await.map(Some),
-            None => Ok(None),
-        }
-    });
-    let logs_future = tokio::spawn(async {
-        match logs {
-            Some(logs) => logs.execute_stream().await.map(Some),
-            None => Ok(None),
-        }
-    });
-    let (blocks_result, tx_result, logs_result) = try_join!(blocks_future, tx_future, logs_future)
-        .context("Subqueries execution panicked")?;
+    let num_tx_selections = tx_selections.len();
+    let dataframes = std::iter::once(blocks)
+        .chain(tx_selections)
+        .chain(logs_selections)
+        .collect();
+    let mut plans = create_plans(dataframes).await?.into_iter();
+    let blocks_plan = plans.next().expect("Blocks are always planned");
+    let tx_plans = plans.by_ref().take(num_tx_selections).collect_vec();
+    let logs_plans = plans.collect_vec();
+    let selection = |plans: Vec<_>, key| (!plans.is_empty()).then_some(Selection { plans, key });
 
-    Ok((blocks_result?, tx_result?, logs_result?))
+    Ok((
+        blocks_plan,
+        selection(tx_plans, tx_key),
+        selection(logs_plans, logs_key),
+    ))
 }
 
-#[instrument(skip_all)]
-fn convert_to_json(
-    stream: impl Stream<Item = Result<RecordBatch, DataFusionError>>,
-) -> impl Stream<Item = Result<JsonMap<String, Value>, DataFusionError>> {
-    stream.flat_map(|record_batch| {
-        let entries = match record_batch
-            .and_then(|batch| record_batches_to_json_rows(&[&batch]).map_err(From::from))
-        {
-            Ok(vec) => vec.into_iter().map(Ok).collect_vec(),
-            Err(e) => vec![Err(e)],
-        };
-        futures::stream::iter(entries)
-    })
+/// Camel-cased primary key columns of the registered table
+async fn primary_key(ctx: &SessionContext, table: &str) -> Result<Vec<String>, QueryError> {
+    let provider = ctx.table_provider(table).await?;
+    let schema = provider.schema();
+    let indices = provider
+        .constraints()
+        .and_then(|constraints| {
+            constraints.iter().find_map(|constraint| match constraint {
+                Constraint::PrimaryKey(indices) => Some(indices),
+                _ => None,
+            })
+        })
+        .with_context(|| format!("Table {table} has no primary key"))?;
+    Ok(indices
+        .iter()
+        .map(|&i| RenameRule::CamelCase.apply_to_field(schema.field(i).name()))
+        .collect())
 }
+
+/// Sorting is cheap as the files are stored in the primary key order,
+/// so DataFusion only has to keep the order of the scanned rows.
+/// The key columns are always selected to merge the selections.
+fn sort_selections(
+    selections: Vec<DataFrame>,
+    columns: Vec<&str>,
+    key: &[String],
+) -> Result<Vec<DataFrame>, DataFusionError> {
+    let columns = merge_ordered(columns, key.iter().map(String::as_str).collect());
+    let sort = key
+        .iter()
+        .map(|column| col(Column::from_name(column)).sort(true, true))
+        .collect_vec();
+    selections
+        .into_iter()
+        .map(|df| select_columns(camel_case_columns(df)?, &columns)?.sort(sort.clone()))
+        .collect()
+}
+
+async fn create_plans(
+    dataframes: Vec<DataFrame>,
+) -> Result<Vec<Arc<dyn ExecutionPlan>>, QueryError> {
+    let futures = dataframes
+        .into_iter()
+        .map(|df| tokio::spawn(df.create_physical_plan()));
+    let plans = futures::future::try_join_all(futures)
+        .await
+        .context("Subqueries planning panicked")?;
+    Ok(plans.into_iter().collect::<Result<_, _>>()?)
+}
+
+/// Writes the JSON array of blocks with their transactions and logs in a single pass.
+/// All the streams should be sorted by the block number.
 #[instrument(skip_all)]
-fn build_response<'l>(
-    query: &'l BatchRequest,
-    headers: impl Stream<Item = Result<JsonMap<String, Value>, DataFusionError>> + Unpin + 'l,
-    transactions: Option<impl Stream<Item = Result<JsonMap<String, Value>, DataFusionError>> + 'l>,
-    logs: Option<impl Stream<Item = Result<JsonMap<String, Value>, DataFusionError>> + 'l>,
-) -> impl Stream<Item = anyhow::Result<Value>> + 'l {
-    let include_tx = transactions.is_some();
-    let include_logs = logs.is_some();
-    let mut transactions = transactions.map(|stream| Box::pin(stream.peekable()));
-    let mut logs = logs.map(|stream| Box::pin(stream.peekable()));
-
-    async fn block_rows<S: Stream<Item = Result<JsonMap<String, Value>, DataFusionError>>>(
-        stream: &mut Option<Pin<Box<Peekable<S>>>>,
-        block_number: u64,
-    ) -> Result<Vec<JsonMap<String, Value>>, DataFusionError> {
-        if let Some(stream) = stream.as_mut() {
-            consume_while(stream.as_mut(), |tx: &JsonMap<String, Value>| {
-                tx.get("blockNumber").unwrap().as_u64().unwrap() == block_number
-            })
-            .await
-        } else {
-            Ok(Vec::new())
-        }
-    }
+pub async fn write_response(
+    query: &BatchRequest,
+    mut headers: SendableRecordBatchStream,
+    transactions: Option<SendableRecordBatchStream>,
+    logs: Option<SendableRecordBatchStream>,
+) -> anyhow::Result<Vec<u8>> {
+    let fields = query.fields.as_ref();
+    let mut transactions =
+        transactions.map(|stream| BlockRows::new(stream, fields.map(|f| &f.transaction)));
+    let mut logs = logs.map(|stream| BlockRows::new(stream, fields.map(|f| &f.log)));
+    let mut header_writer = ObjectWriter::new(&headers.schema(), &[SELECTED_COLUMN], &[]);
 
-    try_stream! {
-        let mut last_block = None;
-        let mut headers = headers.enumerate();
-        while let Some((header_index, header)) = headers.next().await {
-            let header = header?;
-            let mut block = JsonMap::new();
-            let number = header
-                .get("number")
-                .context("Found block without number")?
-                .as_u64()
-                .unwrap();
-            let mut include_block = false;
-
-            block.insert("header".to_owned(), serde_json::to_value(&header)?);
-
-            let mut transactions = block_rows(&mut transactions, number).await?;
-            for tx in transactions.iter_mut() {
-                tx.retain(|key, _| key != "blockNumber");
-                add_nulls(tx, query.fields.as_ref().map(|f| &f.transaction));
-            }
-            if !transactions.is_empty() {
-                include_block = true;
-            }
-            if !transactions.is_empty() || include_tx {
-                block.insert(
-                    "transactions".to_owned(),
-                    serde_json::to_value(transactions)?,
-                );
-            }
+    let mut result = vec![b'['];
+    let mut block = Vec::new();
+    // Only the last of the skipped blocks is written, at the end of the result
+    let mut last_block = Vec::new();
+    let mut is_first = true;
+    while let Some(batch) = headers.next().await {
+        let batch = batch?;
+        let numbers = block_numbers(&batch, "number")?;
+        let selected = batch
+            .column_by_name(SELECTED_COLUMN)
+            .map(|column| column.as_boolean().clone());
+        header_writer.set_batch(&batch)?;
+        for row in 0..batch.num_rows() {
+            let number = numbers.value(row);
+            let mut include_block = selected
+                .as_ref()
+                .is_some_and(|selected| selected.is_valid(row) && selected.value(row));
 
-            let mut logs = block_rows(&mut logs, number).await?;
-            for log in logs.iter_mut() {
-                log.retain(|key, _| key != "blockNumber");
-                add_nulls(log, query.fields.as_ref().map(|f| &f.log));
-            }
-            if !logs.is_empty() {
-                include_block = true;
+            block.clear();
+            block.extend_from_slice(b"{\"header\":");
+            header_writer.write_row(row, &mut block);
+            if let Some(transactions) = transactions.as_mut() {
+                block.extend_from_slice(b",\"transactions\":");
+                include_block |= transactions.write_block(number, &mut block).await?;
             }
-            if !logs.is_empty() || include_logs {
-                block.insert(
-                    "logs".to_owned(),
-                    serde_json::to_value(logs)?,
-                );
+            if let Some(logs) = logs.as_mut() {
+                block.extend_from_slice(b",\"logs\":");
+                include_block |= logs.write_block(number, &mut block).await?;
             }
+            block.push(b'}');
 
-            if include_block || query.include_all_blocks || header_index == 0 {
-                yield Value::Object(block);
-                last_block = None;
+            if include_block || query.include_all_blocks || is_first {
+                push_block(&mut result, &block);
+                last_block.clear();
             } else {
-                last_block = Some(block);
+                std::mem::swap(&mut block, &mut last_block);
             }
+            is_first = false;
         }
-        if let Some(block) = last_block {
-            yield Value::Object(block);
-        }
     }
+    if !last_block.is_empty() {
+        push_block(&mut result, &last_block);
+    }
+    result.push(b']');
+    tracing::trace!("Got all result rows");
+    Ok(result)
+}
+
+fn push_block(result: &mut Vec<u8>, block: &[u8]) {
+    if result.len() > 1 {
+        result.push(b',');
+    }
+    result.extend_from_slice(block);
 }
 
-async fn consume_while<T, E>(
-    mut stream: std::pin::Pin<&mut Peekable<impl Stream<Item = Result<T, E>>>>,
-    f: impl Fn(&T) -> bool,
-) -> Result<Vec<T>, E> {
-    let mut result = Vec::new();
-    while let Some(item) = stream
-        .as_mut()
-        .next_if(|item| match item {
-            Err(_) => true,
-            Ok(item) => f(item),
-        })
-        .await
-    {
-        match item {
-            Ok(item) => {
-                result.push(item);
-            }
-            Err(e) => return Err(e),
+/// Writes the rows of a table stream grouped by the block number
+struct BlockRows {
+    stream: SendableRecordBatchStream,
+    writer: ObjectWriter,
+    /// Block numbers of the current batch
+    numbers: UInt64Array,
+    position: usize,
+}
+
+impl BlockRows {
+    fn new(stream: SendableRecordBatchStream, fields: Option<&Vec<String>>) -> Self {
+        let fields = fields.map_or(&[][..], |fields| fields.as_slice());
+        Self {
+            writer: ObjectWriter::new(&stream.schema(), &["blockNumber"], fields),
+            stream,
+            numbers: UInt64Array::from(Vec::<u64>::new()),
+            position: 0,
         }
     }
-    Ok(result)
-}
 
-#[instrument(skip_all)]
-async fn collect_result(
-    stream: impl Stream<Item = anyhow::Result<Value>>,
-) -> anyhow::Result<QueryResult> {
-    let mut result = Vec::new();
-    tokio::pin!(stream);
-    while let Some(row) = stream.next().await {
-        if result.is_empty() {
-            tracing::trace!("Got first result row");
+    /// Writes the rows of the block as a JSON array. Returns whether there were any.
+    async fn write_block(&mut self, block_number: u64, out: &mut Vec<u8>) -> anyhow::Result<bool> {
+        out.push(b'[');
+        let mut empty = true;
+        loop {
+            if self.position == self.numbers.len() {
+                match self.stream.next().await {
+                    Some(batch) => {
+                        let batch = batch?;
+                        self.numbers = block_numbers(&batch, "blockNumber")?;
+                        self.writer.set_batch(&batch)?;
+                        self.position = 0;
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            if self.numbers.value(self.position) != block_number {
+                break;
+            }
+            if !empty {
+                out.push(b',');
+            }
+            self.writer.write_row(self.position, out);
+            empty = false;
+            self.position += 1;
         }
-        result.push(row?);
+        out.push(b']');
+        Ok(!empty)
     }
-    tracing::trace!("Got all result rows");
-    Ok(result)
+}
+
+fn block_numbers(batch: &RecordBatch, column: &str) -> anyhow::Result<UInt64Array> {
+    let numbers = batch
+        .column_by_name(column)
+        .with_context(|| format!("Found rows without {column}"))?;
+    let numbers = compute::cast(numbers, &DataType::UInt64)?;
+    Ok(numbers.as_primitive::<UInt64Type>().clone())
+}
+
+fn selection_plans(selection: &Option<Selection>) -> &[Arc<dyn ExecutionPlan>] {
+    selection
+        .as_ref()
+        .map_or(&[], |selection| selection.plans.as_slice())
+}
+
+fn total_bytes_scanned(
+    blocks_plan: &Arc<dyn ExecutionPlan>,
+    transactions: &Option<Selection>,
+    logs: &Option<Selection>,
+) -> u64 {
+    std::iter::once(blocks_plan)
+        .chain(selection_plans(transactions))
+        .chain(selection_plans(logs))
+        .map(bytes_scanned)
+        .sum()
+}
+
+fn exec_plan(
+    blocks_plan: &Arc<dyn ExecutionPlan>,
+    transactions: &Option<Selection>,
+    logs: &Option<Selection>,
+) -> ExecPlan {
+    ExecPlan::new(
+        blocks_plan,
+        selection_plans(transactions),
+        selection_plans(logs),
+    )
+}
+
+fn bytes_scanned(plan: &Arc<dyn ExecutionPlan>) -> u64 {
+    let own = plan
+        .metrics()
+        .and_then(|metrics| metrics.sum_by_name("bytes_scanned"))
+        .map(|value| value.as_usize() as u64)
+        .unwrap_or(0);
+    own + plan.children().iter().map(bytes_scanned).sum::<u64>()
 }
 
 fn camel_case_columns(df: DataFrame) -> Result<DataFrame, DataFusionError> {
@@ -376,6 +680,23 @@ fn camel_case_columns(df: DataFrame) -> Result<DataFrame, DataFusionError> {
     )
 }
 
+/// Columns missing in the chunk are selected as nulls. This happens when a column that
+/// newer chunks define in their schema descriptors is requested on an older chunk.
+/// Their type is unknown, so the nulls are strings.
+fn select_columns(df: DataFrame, columns: &[&str]) -> Result<DataFrame, DataFusionError> {
+    let exprs = columns
+        .iter()
+        .map(|&name| {
+            if df.schema().has_column_with_unqualified_name(name) {
+                col(Column::from_name(name))
+            } else {
+                cast(lit(ScalarValue::Null), DataType::Utf8).alias(name)
+            }
+        })
+        .collect_vec();
+    df.select(exprs)
+}
+
 fn block_columns(query: &BatchRequest) -> Vec<&str> {
     merge_ordered(
         vec!["number", "hash", "parentHash"],
@@ -423,18 +744,45 @@ fn merge_ordered<T: Clone + Hash + Eq>(left: Vec<T>, right: Vec<T>) -> Vec<T> {
     result
 }
 
-fn add_nulls(value: &mut JsonMap<String, Value>, fields: Option<&Vec<String>>) {
-    if let Some(fields) = fields {
-        for field in fields {
-            value.entry(field).or_insert(Value::Null);
-        }
-    }
+/// Hex values are stored lowercased, so the requested values are matched case-insensitively
+fn field_in(field: &str, values: &Option<Vec<String>>) -> Option<Expr> {
+    values.as_ref().map(|values| {
+        col(field).in_list(
+            values
+                .iter()
+                .map(|value| lit(value.to_ascii_lowercase()))
+                .collect(),
+            false,
+        )
+    })
 }
 
-fn field_in(field: &str, values: &Option<Vec<String>>) -> Option<Expr> {
-    values
-        .as_ref()
-        .map(|values| col(field).in_list(values.iter().map(lit).collect(), false))
+/// Like [`field_in`] but with `address_topics` 20-byte addresses are matched as indexed topics
+fn topic_in(field: &str, values: &Option<Vec<String>>, address_topics: bool) -> Option<Expr> {
+    values.as_ref().map(|values| {
+        col(field).in_list(
+            values
+                .iter()
+                .map(|value| lit(normalize_topic(value, address_topics)))
+                .collect(),
+            false,
+        )
+    })
+}
+
+/// Left-pads the address with zeros to the 32-byte form used in indexed event parameters
+pub fn address_to_topic(address: &str) -> String {
+    let hex = address.strip_prefix("0x").unwrap_or(address);
+    format!("0x{:0>64}", hex.to_ascii_lowercase())
+}
+
+fn normalize_topic(value: &str, address_topics: bool) -> String {
+    let hex = value.strip_prefix("0x").unwrap_or(value);
+    if address_topics && hex.len() == 40 {
+        address_to_topic(hex)
+    } else {
+        value.to_ascii_lowercase()
+    }
 }
 
 fn all_of(predicates: Vec<Expr>) -> Option<Expr> {
@@ -445,16 +793,296 @@ fn any_of(predicates: Vec<Expr>) -> Option<Expr> {
     predicates.into_iter().reduce(or)
 }
 
-fn union_all(dataframes: Vec<DataFrame>) -> Result<Option<DataFrame>, DataFusionError> {
-    let mut iter = dataframes.into_iter();
-    if let Some(first) = iter.next() {
-        let mut result = first;
-        for df in iter {
-            // TODO: use distinct_by
-            result = result.union_distinct(df)?;
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+    use datafusion::{
+        arrow::{
+            array::{
+                new_null_array, ArrayRef, AsArray, BooleanArray, Int32Array, StringArray,
+                UInt64Array,
+            },
+            datatypes::{DataType, Field, Schema, UInt64Type},
+            record_batch::RecordBatch,
+        },
+        parquet::arrow::ArrowWriter,
+        physical_plan::{memory::MemoryStream, SendableRecordBatchStream},
+    };
+    use itertools::Itertools;
+
+    use super::{
+        address_to_topic, normalize_topic, process_query, select_blocks, write_response,
+        SELECTED_COLUMN,
+    };
+    use crate::query::{
+        context::prepare_query_context,
+        eth::BatchRequest,
+        format::Table,
+        schema::{DatasetSchema, DESCRIPTOR_FILE},
+    };
+
+    fn stream(batches: Vec<RecordBatch>) -> SendableRecordBatchStream {
+        let schema = batches[0].schema();
+        Box::pin(MemoryStream::try_new(batches, schema, None).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_write_response() {
+        let headers = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("number", DataType::UInt64, false),
+                Field::new("hash", DataType::Utf8, true),
+                Field::new(SELECTED_COLUMN, DataType::Boolean, true),
+            ])),
+            vec![
+                Arc::new(UInt64Array::from(vec![1, 2, 3, 4, 5])),
+                Arc::new(StringArray::from(vec![
+                    Some("0x01"),
+                    None,
+                    Some("0x03"),
+                    Some("0x04"),
+                    Some("0x05"),
+                ])),
+                Arc::new(BooleanArray::from(vec![
+                    Some(false),
+                    None,
+                    Some(true),
+                    Some(false),
+                    None,
+                ])),
+            ],
+        )
+        .unwrap();
+        let tx_schema = Arc::new(Schema::new(vec![
+            Field::new("blockNumber", DataType::UInt64, false),
+            Field::new("transactionIndex", DataType::Int32, false),
+            Field::new("from", DataType::Utf8, true),
+        ]));
+        let tx_batch = |rows: Vec<(u64, i32, Option<&str>)>| {
+            RecordBatch::try_new(
+                tx_schema.clone(),
+                vec![
+                    Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| r.0))),
+                    Arc::new(Int32Array::from_iter_values(rows.iter().map(|r| r.1))),
+                    Arc::new(StringArray::from_iter(rows.iter().map(|r| r.2))),
+                ],
+            )
+            .unwrap()
+        };
+        let transactions = vec![
+            tx_batch(vec![(2, 0, Some("0xa"))]),
+            tx_batch(vec![]),
+            tx_batch(vec![(2, 1, None)]),
+        ];
+        let query: BatchRequest = serde_json::from_value(serde_json::json!({
+            "fromBlock": 1,
+            "fields": {"transaction": {"from": true}},
+        }))
+        .unwrap();
+
+        let result = write_response(
+            &query,
+            stream(vec![headers]),
+            Some(stream(transactions)),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            concat!(
+                r#"[{"header":{"number":1,"hash":"0x01"},"transactions":[]},"#,
+                r#"{"header":{"number":2},"transactions":[{"transactionIndex":0,"from":"0xa"},{"transactionIndex":1,"from":null}]},"#,
+                r#"{"header":{"number":3,"hash":"0x03"},"transactions":[]},"#,
+                r#"{"header":{"number":5,"hash":"0x05"},"transactions":[]}]"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_select_blocks() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("number", DataType::UInt64, false),
+            Field::new(SELECTED_COLUMN, DataType::Boolean, true),
+        ]));
+        let blocks_batch = |numbers: Vec<u64>, selected: Vec<Option<bool>>| {
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(UInt64Array::from(numbers)),
+                    Arc::new(BooleanArray::from(selected)),
+                ],
+            )
+            .unwrap()
+        };
+        let blocks = [
+            blocks_batch(vec![], vec![]),
+            blocks_batch(vec![1, 2, 3], vec![Some(false), None, Some(true)]),
+            blocks_batch(vec![4, 5, 6], vec![Some(false), None, None]),
+            blocks_batch(vec![], vec![]),
+        ];
+        let tx_schema = Arc::new(Schema::new(vec![Field::new(
+            "blockNumber",
+            DataType::UInt64,
+            false,
+        )]));
+        let transactions = Table {
+            name: "transactions",
+            schema: tx_schema.clone(),
+            batches: vec![RecordBatch::try_new(
+                tx_schema,
+                vec![Arc::new(UInt64Array::from(vec![2, 2, 5]))],
+            )
+            .unwrap()],
+        };
+
+        let numbers = |table: Table| {
+            assert_eq!(table.schema.fields().len(), 1);
+            table
+                .batches
+                .iter()
+                .flat_map(|batch| {
+                    assert_eq!(batch.schema(), table.schema);
+                    batch
+                        .column(0)
+                        .as_primitive::<UInt64Type>()
+                        .values()
+                        .to_vec()
+                })
+                .collect::<Vec<_>>()
+        };
+        let table = select_blocks(&schema, &blocks, &[transactions], false).unwrap();
+        assert_eq!(numbers(table), [1, 2, 3, 5, 6]);
+        let table = select_blocks(&schema, &blocks, &[], true).unwrap();
+        assert_eq!(numbers(table), [1, 2, 3, 4, 5, 6]);
+    }
+
+    /// Writes two blocks with a row per block in each table
+    fn write_chunk(dir: &Path, schema: &DatasetSchema) {
+        let column = |data_type: &DataType| -> ArrayRef {
+            match data_type {
+                DataType::UInt64 => Arc::new(UInt64Array::from(vec![1, 2])),
+                DataType::Int32 => Arc::new(Int32Array::from(vec![0, 0])),
+                DataType::Utf8 => Arc::new(StringArray::from(vec!["0x01", "0x02"])),
+                data_type => new_null_array(data_type, 2),
+            }
+        };
+        std::fs::create_dir_all(dir).unwrap();
+        for table in &schema.tables {
+            let table_schema = Arc::new(table.schema.clone());
+            let columns = table_schema
+                .fields()
+                .iter()
+                .map(|field| column(field.data_type()))
+                .collect();
+            let batch = RecordBatch::try_new(table_schema.clone(), columns).unwrap();
+            let file = std::fs::File::create(dir.join(&table.file)).unwrap();
+            let mut writer = ArrowWriter::try_new(file, table_schema, None).unwrap();
+            writer.write(&batch).unwrap();
+            writer.close().unwrap();
         }
-        Ok(Some(result))
-    } else {
-        Ok(None)
+    }
+
+    /// The legacy tables with an extra `l1_block_number` column in the blocks table
+    fn descriptor() -> String {
+        let mut schema = DatasetSchema::legacy();
+        let blocks = &mut schema.tables[0];
+        let mut fields = blocks.schema.fields().iter().cloned().collect_vec();
+        fields.push(Arc::new(Field::new(
+            "l1_block_number",
+            DataType::UInt64,
+            true,
+        )));
+        blocks.schema = Schema::new(fields);
+        let type_name = |data_type: &DataType| match data_type {
+            DataType::Timestamp(unit, _) => format!("Timestamp({unit:?})"),
+            data_type => format!("{data_type:?}"),
+        };
+        let tables = schema
+            .tables
+            .iter()
+            .map(|table| {
+                serde_json::json!({
+                    "name": table.name,
+                    "columns": table.schema.fields().iter().map(|field| serde_json::json!({
+                        "name": field.name(),
+                        "type": type_name(field.data_type()),
+                        "nullable": field.is_nullable(),
+                    })).collect_vec(),
+                    "primary_key": table
+                        .primary_key
+                        .iter()
+                        .map(|&i| table.schema.field(i).name())
+                        .collect_vec(),
+                })
+            })
+            .collect_vec();
+        serde_json::json!({ "tables": tables }).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_mixed_schemas() {
+        let dir = PathBuf::try_from(std::env::temp_dir())
+            .unwrap()
+            .join(format!("mixed-schemas-test-{}", std::process::id()));
+        let legacy = dir.join("0000000000/0000000001-0000000002-legacy00");
+        write_chunk(&legacy, &DatasetSchema::legacy());
+        let described = dir.join("0000000000/0000000003-0000000004-descript");
+        let descriptor = descriptor();
+        write_chunk(&described, &DatasetSchema::parse(&descriptor).unwrap());
+        std::fs::write(described.join(DESCRIPTOR_FILE), descriptor).unwrap();
+
+        let query: BatchRequest = serde_json::from_value(serde_json::json!({
+            "fromBlock": 1,
+            "includeAllBlocks": true,
+            "fields": {"block": {"number": true, "l1BlockNumber": true}},
+        }))
+        .unwrap();
+        let mut l1_block_numbers = Vec::new();
+        for chunk in [&legacy, &described] {
+            let ctx = prepare_query_context(chunk).await.unwrap();
+            let output = process_query(&ctx, query.clone()).await.unwrap();
+            let blocks: Vec<serde_json::Value> = serde_json::from_slice(&output.data).unwrap();
+            assert_eq!(blocks.len(), 2);
+            l1_block_numbers.extend(
+                blocks
+                    .iter()
+                    .map(|block| block["header"]["l1BlockNumber"].as_u64()),
+            );
+        }
+        // The legacy chunk doesn't have the column
+        assert_eq!(l1_block_numbers, [None, None, Some(1), Some(2)]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_normalize_topic() {
+        let topic = "0x0000000000000000000000003fc91a3afd70395cd496c647d5a6cc9d4b2b7fad";
+        assert_eq!(
+            address_to_topic("0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD"),
+            topic
+        );
+        assert_eq!(
+            normalize_topic("0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD", true),
+            topic
+        );
+        assert_eq!(
+            normalize_topic("0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD", false),
+            "0x3fc91a3afd70395cd496c647d5a6cc9d4b2b7fad"
+        );
+        assert_eq!(
+            normalize_topic(&topic.to_uppercase().replace("0X", "0x"), true),
+            topic
+        );
+        assert_eq!(
+            normalize_topic(
+                "0xDDF252AD1BE2C89B69C2B068FC378DAA952BA7F163C4A11628F55A4DF523B3EF",
+                true
+            ),
+            "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"
+        );
     }
 }
@@ -0,0 +1,97 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use datafusion::physical_plan::{displayable, ExecutionPlan};
+use serde::Serialize;
+
+/// Executed physical plans of the subqueries with their metrics
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecPlan {
+    pub blocks: PlanNode,
+    /// One plan per selection, their outputs are merged by the primary key
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub transactions: Vec<PlanNode>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub logs: Vec<PlanNode>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanNode {
+    pub operator: String,
+    /// Metrics aggregated over all partitions, e.g. `output_rows`, `bytes_scanned`
+    /// and `elapsed_compute` (in nanoseconds)
+    pub metrics: BTreeMap<String, usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<PlanNode>,
+}
+
+impl ExecPlan {
+    /// Should be called after the plans have been executed to get meaningful metrics
+    pub fn new(
+        blocks: &Arc<dyn ExecutionPlan>,
+        transactions: &[Arc<dyn ExecutionPlan>],
+        logs: &[Arc<dyn ExecutionPlan>],
+    ) -> Self {
+        Self {
+            blocks: PlanNode::new(blocks),
+            transactions: transactions.iter().map(PlanNode::new).collect(),
+            logs: logs.iter().map(PlanNode::new).collect(),
+        }
+    }
+}
+
+impl PlanNode {
+    fn new(plan: &Arc<dyn ExecutionPlan>) -> Self {
+        let metrics = plan
+            .metrics()
+            .map(|metrics| {
+                metrics
+                    .aggregate_by_name()
+                    .timestamps_removed()
+                    .iter()
+                    .map(|metric| (metric.value().name().to_owned(), metric.value().as_usize()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            operator: displayable(plan.as_ref())
+                .one_line()
+                .to_string()
+                .trim()
+                .to_owned(),
+            metrics,
+            children: plan.children().iter().map(PlanNode::new).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::prelude::*;
+
+    use super::ExecPlan;
+
+    #[tokio::test]
+    async fn test_exec_plan() {
+        let ctx = SessionContext::new();
+        let plan = ctx
+            .sql("SELECT * FROM (VALUES (1), (2), (3)) AS t(x) WHERE x > 1")
+            .await
+            .unwrap()
+            .create_physical_plan()
+            .await
+            .unwrap();
+        datafusion::physical_plan::collect(plan.clone(), ctx.task_ctx())
+            .await
+            .unwrap();
+
+        let exec_plan = ExecPlan::new(&plan, &[], &[]);
+        let filter = std::iter::successors(Some(&exec_plan.blocks), |node| node.children.first())
+            .find(|node| node.operator.starts_with("FilterExec"))
+            .unwrap();
+        assert_eq!(filter.metrics.get("output_rows"), Some(&2));
+        assert!(filter.metrics.contains_key("elapsed_compute"));
+
+        let json = serde_json::to_value(&exec_plan).unwrap();
+        assert!(json.get("transactions").is_none());
+    }
+}
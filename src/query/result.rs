@@ -1,41 +1,121 @@
 use anyhow::Result;
+use bytes::Bytes;
 
 use crate::util::hash::sha3_256;
 
-use super::processor;
+use super::{
+    compression::Compression,
+    format::{encode_tables, ResultFormat},
+    processor,
+    profile::ExecPlan,
+};
 
 #[derive(Debug, Clone)]
 pub struct QueryResult {
-    pub raw_data: Vec<u8>,
-    pub compressed_data: Vec<u8>,
+    /// Encoded result compressed with `compression`. Cloning the result shares the data.
+    pub data: Bytes,
+    /// Size of the uncompressed data
     pub data_size: usize,
     pub compressed_size: usize,
+    /// Hash of the uncompressed data
     pub data_sha3_256: Vec<u8>,
     pub num_read_chunks: usize,
+    pub bytes_scanned: u64,
+    pub format: ResultFormat,
+    pub compression: Compression,
+    /// JSON-encoded [`ExecPlan`] if the query requested profiling and it isn't embedded in the data
+    pub exec_plan: Option<Vec<u8>>,
+}
+
+/// How the result should be encoded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Encoding {
+    pub format: ResultFormat,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    /// Wrap the JSON result into a `{ "data", "execPlan" }` envelope if the query was profiled
+    pub embed_exec_plan: bool,
 }
 
 impl QueryResult {
-    pub fn new(values: processor::QueryResult, num_read_chunks: usize) -> Result<Self> {
-        use flate2::write::GzEncoder;
-        use std::io::Write;
+    pub fn new(
+        output: processor::QueryOutput,
+        encoding: Encoding,
+        num_read_chunks: usize,
+    ) -> Result<Self> {
+        Self::from_data(
+            output.data,
+            encoding,
+            num_read_chunks,
+            output.bytes_scanned,
+            output.exec_plan,
+        )
+    }
 
-        let data = serde_json::to_vec(&values)?;
-        let data_size = data.len();
+    pub fn from_tables(
+        output: processor::TablesOutput,
+        encoding: Encoding,
+        num_read_chunks: usize,
+    ) -> Result<Self> {
+        let data = encode_tables(&output.tables, encoding.format)?;
+        Self::from_data(
+            data,
+            encoding,
+            num_read_chunks,
+            output.bytes_scanned,
+            output.exec_plan,
+        )
+    }
 
-        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
-        encoder.write_all(&data)?;
-        let compressed_data = encoder.finish()?;
-        let compressed_size = compressed_data.len();
+    /// SQL query result encoded as an uncompressed JSON array of rows
+    pub fn from_rows(rows: &[serde_json::Value], num_read_chunks: usize) -> Result<Self> {
+        let data = serde_json::to_vec(rows)?;
+        Self::from_data(data, Encoding::default(), num_read_chunks, 0, None)
+    }
 
+    fn from_data(
+        data: Vec<u8>,
+        encoding: Encoding,
+        num_read_chunks: usize,
+        bytes_scanned: u64,
+        exec_plan: Option<ExecPlan>,
+    ) -> Result<Self> {
+        let (data, exec_plan) = match exec_plan {
+            Some(plan) if encoding.embed_exec_plan => (embed_exec_plan(data, &plan)?, None),
+            plan => (
+                data,
+                plan.map(|plan| serde_json::to_vec(&plan)).transpose()?,
+            ),
+        };
+        let data_size = data.len();
         let hash = sha3_256(&data);
 
+        let compressed_data = match encoding.compression {
+            Compression::None => data,
+            codec => codec.compress(&data, encoding.compression_level)?,
+        };
+        let compressed_size = compressed_data.len();
+
         Ok(Self {
-            raw_data: data,
-            compressed_data,
+            data: compressed_data.into(),
             data_size,
             compressed_size,
             data_sha3_256: hash,
             num_read_chunks,
+            bytes_scanned,
+            format: encoding.format,
+            compression: encoding.compression,
+            exec_plan,
         })
     }
 }
+
+fn embed_exec_plan(data: Vec<u8>, plan: &ExecPlan) -> Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(data.len() + 32);
+    result.extend_from_slice(br#"{"data":"#);
+    result.extend_from_slice(&data);
+    result.extend_from_slice(br#","execPlan":"#);
+    serde_json::to_writer(&mut result, plan)?;
+    result.push(b'}');
+    Ok(result)
+}
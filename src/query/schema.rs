@@ -0,0 +1,302 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use camino::Utf8Path as Path;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+/// Optional file in the chunk directory describing the tables stored in the chunk.
+/// Chunks without it are assumed to have the legacy EVM layout.
+pub const DESCRIPTOR_FILE: &str = "schema.json";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatasetSchema {
+    pub tables: Vec<TableSchema>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSchema {
+    pub name: String,
+    /// Name of the parquet file relative to the chunk directory
+    pub file: String,
+    pub schema: Schema,
+    pub primary_key: Vec<usize>,
+    /// Column with the block number of each row, `None` if the rows aren't tied to blocks
+    pub block_column: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DescriptorFile {
+    tables: Vec<TableDescriptor>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TableDescriptor {
+    name: String,
+    file: Option<String>,
+    columns: Vec<ColumnDescriptor>,
+    #[serde(default)]
+    primary_key: Vec<String>,
+    /// Defaults to `number` in the `blocks` table and `block_number` in the others if present
+    block_column: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ColumnDescriptor {
+    name: String,
+    #[serde(rename = "type")]
+    data_type: String,
+    #[serde(default = "default_nullable")]
+    nullable: bool,
+}
+
+fn default_nullable() -> bool {
+    true
+}
+
+lazy_static! {
+    static ref BLOCKS_SCHEMA: Schema = Schema::new(vec![
+        Field::new("number", DataType::UInt64, false),
+        Field::new("hash", DataType::Utf8, false),
+        Field::new("parent_hash", DataType::Utf8, true),
+        Field::new("nonce", DataType::Utf8, true),
+        Field::new("sha3_uncles", DataType::Utf8, true),
+        Field::new("logs_bloom", DataType::Utf8, true),
+        Field::new("transactions_root", DataType::Utf8, true),
+        Field::new("state_root", DataType::Utf8, true),
+        Field::new("receipts_root", DataType::Utf8, true),
+        Field::new("mix_hash", DataType::Utf8, true),
+        Field::new("miner", DataType::Utf8, true),
+        Field::new("difficulty", DataType::Utf8, true),
+        Field::new("total_difficulty", DataType::Utf8, true),
+        Field::new("extra_data", DataType::Utf8, true),
+        Field::new("size", DataType::Int32, true),
+        Field::new("gas_limit", DataType::Utf8, true),
+        Field::new("gas_used", DataType::Utf8, true),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, None),
+            true
+        ),
+        Field::new("base_fee_per_gas", DataType::Utf8, true),
+        Field::new("extra_data_size", DataType::Int64, true),
+    ]);
+    static ref TRANSACTIONS_SCHEMA: Schema = Schema::new(vec![
+        Field::new("block_number", DataType::UInt64, false),
+        Field::new("transaction_index", DataType::Int32, false),
+        Field::new("from", DataType::Utf8, true),
+        Field::new("gas", DataType::Utf8, true),
+        Field::new("gas_price", DataType::Utf8, true),
+        Field::new("max_fee_per_gas", DataType::Utf8, true),
+        Field::new("max_priority_fee_per_gas", DataType::Utf8, true),
+        Field::new("hash", DataType::Utf8, true),
+        Field::new("input", DataType::Utf8, true),
+        Field::new("nonce", DataType::Int64, true),
+        Field::new("to", DataType::Utf8, true),
+        Field::new("value", DataType::Utf8, true),
+        Field::new("v", DataType::Utf8, true),
+        Field::new("r", DataType::Utf8, true),
+        Field::new("s", DataType::Utf8, true),
+        Field::new("y_parity", DataType::Int8, true),
+        Field::new("chain_id", DataType::UInt64, true),
+        Field::new("sighash", DataType::Utf8, true),
+        Field::new("gas_used", DataType::Utf8, true),
+        Field::new("cumulative_gas_used", DataType::Utf8, true),
+        Field::new("effective_gas_price", DataType::Utf8, true),
+        Field::new("contract_address", DataType::Utf8, true),
+        Field::new("type", DataType::UInt8, true),
+        Field::new("status", DataType::Int8, true),
+        Field::new("input_size", DataType::UInt64, true),
+        Field::new("_idx", DataType::Int32, true),
+    ]);
+    static ref LOGS_SCHEMA: Schema = Schema::new(vec![
+        Field::new("block_number", DataType::UInt64, false),
+        Field::new("transaction_index", DataType::Int32, false),
+        Field::new("log_index", DataType::Int32, false),
+        Field::new("transaction_hash", DataType::Utf8, true),
+        Field::new("address", DataType::Utf8, true),
+        Field::new("data", DataType::Utf8, true),
+        Field::new("topic0", DataType::Utf8, true),
+        Field::new("topic1", DataType::Utf8, true),
+        Field::new("topic2", DataType::Utf8, true),
+        Field::new("topic3", DataType::Utf8, true),
+        Field::new("data_size", DataType::UInt64, true),
+        Field::new("_idx", DataType::Int32, true),
+    ]);
+}
+
+impl DatasetSchema {
+    /// Reads the descriptor from the chunk directory or falls back to the legacy schema
+    pub fn load(chunk_path: &Path) -> Result<Self> {
+        let path = chunk_path.join(DESCRIPTOR_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&contents).with_context(|| format!("Invalid {path}")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::legacy()),
+            Err(e) => Err(anyhow::Error::new(e).context(format!("Couldn't read {path}"))),
+        }
+    }
+
+    /// Tables of the chunks created before the descriptors were introduced
+    pub fn legacy() -> Self {
+        Self {
+            tables: vec![
+                TableSchema::new("blocks", BLOCKS_SCHEMA.clone(), vec![0], "number"),
+                TableSchema::new(
+                    "transactions",
+                    TRANSACTIONS_SCHEMA.clone(),
+                    vec![0, 1],
+                    "block_number",
+                ),
+                TableSchema::new("logs", LOGS_SCHEMA.clone(), vec![0, 1, 2], "block_number"),
+            ],
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let descriptor: DescriptorFile = serde_json::from_str(contents)?;
+        let tables = descriptor
+            .tables
+            .into_iter()
+            .map(|table| {
+                let fields = table
+                    .columns
+                    .iter()
+                    .map(|column| {
+                        let data_type = parse_data_type(&column.data_type)
+                            .with_context(|| format!("Column {}.{}", table.name, column.name))?;
+                        Ok(Field::new(&column.name, data_type, column.nullable))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let schema = Schema::new(fields);
+                let primary_key = table
+                    .primary_key
+                    .iter()
+                    .map(|column| {
+                        schema.index_of(column).map_err(|_| {
+                            anyhow!("Unknown primary key column {}.{column}", table.name)
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                let block_column = match table.block_column {
+                    Some(column) => {
+                        ensure!(
+                            schema.index_of(&column).is_ok(),
+                            "Unknown block column {}.{column}",
+                            table.name
+                        );
+                        Some(column)
+                    }
+                    None => {
+                        let default = if table.name == "blocks" {
+                            "number"
+                        } else {
+                            "block_number"
+                        };
+                        schema.index_of(default).ok().map(|_| default.to_owned())
+                    }
+                };
+                Ok(TableSchema {
+                    file: table
+                        .file
+                        .unwrap_or_else(|| format!("{}.parquet", table.name)),
+                    name: table.name,
+                    schema,
+                    primary_key,
+                    block_column,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { tables })
+    }
+}
+
+impl TableSchema {
+    fn new(name: &str, schema: Schema, primary_key: Vec<usize>, block_column: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            file: format!("{name}.parquet"),
+            schema,
+            primary_key,
+            block_column: Some(block_column.to_owned()),
+        }
+    }
+}
+
+fn parse_data_type(name: &str) -> Result<DataType> {
+    let data_type = match name {
+        "Boolean" => DataType::Boolean,
+        "Int8" => DataType::Int8,
+        "Int16" => DataType::Int16,
+        "Int32" => DataType::Int32,
+        "Int64" => DataType::Int64,
+        "UInt8" => DataType::UInt8,
+        "UInt16" => DataType::UInt16,
+        "UInt32" => DataType::UInt32,
+        "UInt64" => DataType::UInt64,
+        "Float32" => DataType::Float32,
+        "Float64" => DataType::Float64,
+        "Utf8" => DataType::Utf8,
+        "LargeUtf8" => DataType::LargeUtf8,
+        "Binary" => DataType::Binary,
+        "LargeBinary" => DataType::LargeBinary,
+        "Timestamp(Second)" => DataType::Timestamp(TimeUnit::Second, None),
+        "Timestamp(Millisecond)" => DataType::Timestamp(TimeUnit::Millisecond, None),
+        "Timestamp(Microsecond)" => DataType::Timestamp(TimeUnit::Microsecond, None),
+        "Timestamp(Nanosecond)" => DataType::Timestamp(TimeUnit::Nanosecond, None),
+        _ => bail!("Unsupported column type: {name}"),
+    };
+    Ok(data_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::DataType;
+
+    use super::DatasetSchema;
+    use crate::util::tests::tests_data;
+
+    const DESCRIPTOR: &str = r#"{
+        "tables": [{
+            "name": "blocks",
+            "columns": [
+                {"name": "number", "type": "UInt64", "nullable": false},
+                {"name": "hash", "type": "Utf8", "nullable": false},
+                {"name": "size", "type": "Int64"},
+                {"name": "l1_block_number", "type": "UInt64"}
+            ],
+            "primary_key": ["number"]
+        }]
+    }"#;
+
+    #[test]
+    fn test_load_legacy() {
+        let chunk = tests_data().join("0017881390/0017881390-0017882786-32ee9457");
+        let schema = DatasetSchema::load(&chunk).unwrap();
+        assert_eq!(schema, DatasetSchema::legacy());
+        let names: Vec<_> = schema.tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["blocks", "transactions", "logs"]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let descriptor = DatasetSchema::parse(DESCRIPTOR).unwrap();
+        let blocks = &descriptor.tables[0];
+        assert_eq!(blocks.file, "blocks.parquet");
+        assert_eq!(blocks.primary_key, [0]);
+        let size = blocks.schema.field_with_name("size").unwrap();
+        assert_eq!(size.data_type(), &DataType::Int64);
+        assert!(size.is_nullable());
+        assert!(!blocks
+            .schema
+            .field_with_name("number")
+            .unwrap()
+            .is_nullable());
+
+        assert_eq!(blocks.block_column.as_deref(), Some("number"));
+
+        assert!(DatasetSchema::parse(&DESCRIPTOR.replace("Int64", "Decimal")).is_err());
+        assert!(DatasetSchema::parse(&DESCRIPTOR.replace(r#"["number"]"#, r#"["id"]"#)).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use datafusion::{
+    arrow::json::writer::record_batches_to_json_rows, common::Column, error::DataFusionError,
+    execution::context::SQLOptions, prelude::*,
+};
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::instrument;
+
+use super::{error::QueryError, schema::DatasetSchema};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SqlRequest {
+    pub query: String,
+    pub from_block: u64,
+    pub to_block: Option<u64>,
+}
+
+/// Runs a read-only query over the chunk's tables restricted to the requested block range.
+/// Tables without a block column in the `schema` are not available.
+/// Fails if the result has more than `max_rows` rows.
+#[instrument(skip_all)]
+pub async fn process_sql(
+    ctx: &SessionContext,
+    schema: &DatasetSchema,
+    request: &SqlRequest,
+    max_rows: usize,
+) -> Result<Vec<Value>, QueryError> {
+    for table in &schema.tables {
+        let name = table.name.as_str();
+        let Some(column) = &table.block_column else {
+            ctx.deregister_table(name)?;
+            continue;
+        };
+        let column = col(Column::from_name(column));
+        let range_filter = match request.to_block {
+            Some(to_block) => column.between(lit(request.from_block), lit(to_block)),
+            None => column.gt_eq(lit(request.from_block)),
+        };
+        let view = ctx.table(name).await?.filter(range_filter)?.into_view();
+        ctx.deregister_table(name)?;
+        ctx.register_table(name, view)?;
+    }
+
+    let options = SQLOptions::new()
+        .with_allow_ddl(false)
+        .with_allow_dml(false)
+        .with_allow_statements(false);
+    let df = ctx
+        .sql_with_options(&request.query, options)
+        .await
+        .map_err(|e| QueryError::BadRequest(format!("Invalid SQL query: {e}")))?;
+    let batches = df.limit(0, Some(max_rows + 1))?.collect().await?;
+    let num_rows: usize = batches.iter().map(|batch| batch.num_rows()).sum();
+    if num_rows > max_rows {
+        return Err(QueryError::BadRequest(format!(
+            "Query result exceeds the limit of {max_rows} rows"
+        )));
+    }
+    let rows = record_batches_to_json_rows(&batches.iter().collect_vec())
+        .map_err(DataFusionError::from)?;
+    Ok(rows.into_iter().map(Value::Object).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::{
+        arrow::{
+            array::{ArrayRef, StringArray, UInt64Array},
+            record_batch::RecordBatch,
+        },
+        datasource::MemTable,
+        prelude::SessionContext,
+    };
+
+    use super::{process_sql, SqlRequest};
+    use crate::{
+        query::{error::QueryError, schema::DatasetSchema},
+        util::tests::tests_data,
+    };
+
+    fn request(query: &str) -> SqlRequest {
+        SqlRequest {
+            query: query.to_owned(),
+            from_block: 17881400,
+            to_block: Some(17881409),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sql() {
+        let root = tests_data().join("0017881390/0017881390-0017882786-32ee9457");
+        let ctx = SessionContext::new();
+        let schema = crate::query::context::register_chunk(&ctx, &root)
+            .await
+            .unwrap();
+
+        let rows = process_sql(&ctx, &schema, &request("SELECT number FROM blocks"), 100)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 10);
+
+        let result = process_sql(&ctx, &schema, &request("SELECT number FROM blocks"), 5).await;
+        assert!(matches!(result, Err(QueryError::BadRequest(_))));
+
+        let result = process_sql(&ctx, &schema, &request("DROP TABLE blocks"), 100).await;
+        assert!(matches!(result, Err(QueryError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn test_tables_without_block_column() {
+        let schema = DatasetSchema::parse(
+            r#"{
+                "tables": [
+                    {"name": "blocks", "columns": [{"name": "number", "type": "UInt64"}]},
+                    {"name": "tokens", "columns": [{"name": "symbol", "type": "Utf8"}]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(schema.tables[1].block_column, None);
+        let ctx = SessionContext::new();
+        for (table, column) in [
+            (
+                "blocks",
+                Arc::new(UInt64Array::from(vec![17881399, 17881400])) as ArrayRef,
+            ),
+            ("tokens", Arc::new(StringArray::from(vec!["ETH"]))),
+        ] {
+            let table_schema = schema.tables.iter().find(|t| t.name == table).unwrap();
+            let batch =
+                RecordBatch::try_new(Arc::new(table_schema.schema.clone()), vec![column]).unwrap();
+            let provider = MemTable::try_new(batch.schema(), vec![vec![batch]]).unwrap();
+            ctx.register_table(table, Arc::new(provider)).unwrap();
+        }
+
+        let rows = process_sql(&ctx, &schema, &request("SELECT number FROM blocks"), 100)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        let result = process_sql(&ctx, &schema, &request("SELECT * FROM tokens"), 100).await;
+        assert!(matches!(result, Err(QueryError::BadRequest(_))));
+    }
+}
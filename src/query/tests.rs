@@ -52,22 +52,44 @@ async fn test_query() -> Result<()> {
         let expected: Vec<serde_json::Value> =
             serde_json::from_reader(std::fs::File::open(result_path)?)?;
         info!("Running query {}", query_name);
-        let result = process_query(&ctx, query).await;
-        match result {
-            Ok(result) => {
-                if result != expected {
-                    warn!("Test failed. Saving actual result");
-                    let file = std::fs::File::create(query_path.with_extension("actual.json"))?;
-                    serde_json::to_writer_pretty(std::io::BufWriter::new(file), &result)?;
-                }
-            }
-            Err(err) => warn!("Query failed: {:?}", err),
+        let result = process_query(&ctx, query).await?;
+        let rows: Vec<serde_json::Value> = serde_json::from_slice(&result.data)?;
+        if rows != expected {
+            warn!("Test failed. Saving actual result");
+            let file = std::fs::File::create(query_path.with_extension("actual.json"))?;
+            serde_json::to_writer_pretty(std::io::BufWriter::new(file), &rows)?;
         }
+        assert_eq!(rows, expected, "{query_name}");
     }
     info!("Done");
     Ok(())
 }
 
+/// Each fixture contains two queries that should produce the same non-empty result
+#[tokio::test]
+async fn test_equivalent_queries() -> Result<()> {
+    let ctx = prepare_context().await?;
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures_equivalent");
+    for entry in std::fs::read_dir(&path)? {
+        let path = entry?.path();
+        let mut fixture: serde_json::Value = serde_json::from_reader(std::fs::File::open(&path)?)?;
+        let query: BatchRequest = serde_json::from_value(fixture["query"].take())?;
+        let equivalent: BatchRequest = serde_json::from_value(fixture["equivalent"].take())?;
+        let result = process_query(&ctx, query).await?;
+        let expected = process_query(&ctx, equivalent).await?;
+        assert_eq!(result.data, expected.data, "{}", path.display());
+        let blocks: Vec<serde_json::Value> = serde_json::from_slice(&result.data)?;
+        let matched = blocks
+            .iter()
+            .flat_map(|block| ["logs", "transactions"].map(|key| &block[key]))
+            .filter_map(|items| items.as_array())
+            .map(|items| items.len())
+            .sum::<usize>();
+        assert!(matched > 0, "Nothing matched by {}", path.display());
+    }
+    Ok(())
+}
+
 #[cfg(bench)]
 mod bench {
     extern crate test;
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::{anyhow, Context, Result};
 use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
@@ -8,7 +8,7 @@ use tokio::io::AsyncWriteExt;
 use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
-use crate::types::state::ChunkRef;
+use crate::{config::DownloadsConfig, types::state::ChunkRef};
 
 use super::{
     datasets_index::{DatasetsIndex, RemoteFile},
@@ -16,26 +16,29 @@ use super::{
     local_fs::add_temp_prefix,
 };
 
-lazy_static::lazy_static! {
-    static ref S3_TIMEOUT: std::time::Duration = std::env::var("S3_TIMEOUT")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .map(std::time::Duration::from_secs)
-        .unwrap_or_else(|| std::time::Duration::from_secs(60));
-    static ref S3_READ_TIMEOUT: std::time::Duration = std::env::var("S3_READ_TIMEOUT")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .map(std::time::Duration::from_secs)
-        .unwrap_or_else(|| std::time::Duration::from_secs(3));
-}
-
-#[derive(Default)]
 pub struct ChunkDownloader {
     futures: FuturesUnordered<tokio::task::JoinHandle<(ChunkRef, Result<()>)>>,
     cancel_tokens: HashMap<ChunkRef, CancellationToken>,
+    timeout: Duration,
+    read_timeout: Duration,
 }
 
 impl ChunkDownloader {
+    pub fn new(config: &DownloadsConfig) -> Self {
+        Self {
+            futures: Default::default(),
+            cancel_tokens: Default::default(),
+            timeout: config.s3_timeout(),
+            read_timeout: config.s3_read_timeout(),
+        }
+    }
+
+    /// Only affects the downloads started after the call
+    pub fn set_timeouts(&mut self, config: &DownloadsConfig) {
+        self.timeout = config.s3_timeout();
+        self.read_timeout = config.s3_read_timeout();
+    }
+
     pub fn start_download(
         &mut self,
         chunk: ChunkRef,
@@ -60,16 +63,17 @@ impl ChunkDownloader {
         let headers = datasets_index.get_headers();
         let client = reqwest::ClientBuilder::new()
             .default_headers(headers.clone())
-            .timeout(*S3_TIMEOUT)
-            .read_timeout(*S3_READ_TIMEOUT)
+            .timeout(self.timeout)
+            .read_timeout(self.read_timeout)
             .build()
             .expect("Can't create HTTP client");
+        let total_timeout = self.timeout * num_files as u32;
         self.futures.push(tokio::spawn(async move {
             tokio::select! {
                 result = download_dir(files, dst, &client) => {
                     (chunk, result)
                 }
-                _ = tokio::time::sleep(*S3_TIMEOUT * num_files as u32) => {
+                _ = tokio::time::sleep(total_timeout) => {
                     (chunk, Err(anyhow!("Download timed out")))
                 }
                 _ = cancel_token.cancelled_owned() => {
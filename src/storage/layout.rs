@@ -11,10 +11,10 @@ use tracing::{info, instrument};
 
 use super::Filesystem;
 
-// TODO: use u64
+/// Formatted as a zero-padded decimal number of at least 10 digits
 #[derive(PartialOrd, Ord, PartialEq, Eq, Default, Debug, Clone, Copy, Hash)]
 #[repr(transparent)]
-pub struct BlockNumber(u32);
+pub struct BlockNumber(u64);
 
 impl std::fmt::Display for BlockNumber {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -26,27 +26,27 @@ impl TryFrom<&str> for BlockNumber {
     type Error = anyhow::Error;
 
     fn try_from(s: &str) -> Result<Self, Self::Error> {
-        if s.len() != 10 {
-            bail!("String is not 10-digit decimal number: {}", s);
+        if s.len() < 10 || !s.bytes().all(|c| c.is_ascii_digit()) {
+            bail!("String is not a decimal number of at least 10 digits: {s}");
         }
         Ok(BlockNumber(s.parse()?))
     }
 }
 
-impl From<u32> for BlockNumber {
-    fn from(value: u32) -> Self {
+impl From<u64> for BlockNumber {
+    fn from(value: u64) -> Self {
         BlockNumber(value)
     }
 }
 
-impl AsRef<u32> for BlockNumber {
-    fn as_ref(&self) -> &u32 {
+impl AsRef<u64> for BlockNumber {
+    fn as_ref(&self) -> &u64 {
         &self.0
     }
 }
 
 impl Deref for BlockNumber {
-    type Target = u32;
+    type Target = u64;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
@@ -71,7 +71,7 @@ impl DataChunk {
     // TODO: synchronize with other language implementations
     pub fn from_path(dirname: &str) -> Result<Self> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"(\d{10})/(\d{10})-(\d{10})-(\w{8})$").unwrap();
+            static ref RE: Regex = Regex::new(r"(\d{10,})/(\d{10,})-(\d{10,})-(\w{8})$").unwrap();
         }
         let (top, beg, end, hash) = RE
             .captures(dirname)
@@ -218,9 +218,15 @@ mod tests {
             BlockNumber::try_from("1000000000").unwrap(),
             BlockNumber(1000000000)
         );
+        assert_eq!(
+            BlockNumber::try_from("00000000012345678901").unwrap(),
+            BlockNumber(12345678901)
+        );
         BlockNumber::try_from("20000000000000000000").unwrap_err();
         BlockNumber::try_from("0xdeadbeef").unwrap_err();
+        BlockNumber::try_from("+000000050").unwrap_err();
         assert_eq!(BlockNumber(50).to_string(), "0000000050");
+        assert_eq!(BlockNumber(12345678901).to_string(), "12345678901");
     }
 
     #[test]
@@ -235,6 +241,16 @@ mod tests {
         assert_eq!(chunk.path(), path);
 
         assert_eq!(DataChunk::from_path(&path).unwrap(), chunk);
+
+        let chunk = DataChunk {
+            first_block: 5_000_000_000.into(),
+            last_block: 5_000_000_999.into(),
+            last_hash: "0xabcdef".into(),
+            top: 5_000_000_000.into(),
+        };
+        let path = "5000000000/5000000000-5000000999-0xabcdef";
+        assert_eq!(chunk.path(), path);
+        assert_eq!(DataChunk::from_path(path).unwrap(), chunk);
     }
 
     #[tokio::test]
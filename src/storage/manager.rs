@@ -1,16 +1,28 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::{Context, Result};
 use camino::{Utf8Path as Path, Utf8PathBuf as PathBuf};
+use datafusion::parquet::file::{
+    reader::{FileReader, SerializedFileReader},
+    statistics::Statistics,
+};
 use parking_lot::Mutex;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info, instrument, warn};
+use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
+    config::DownloadsConfig,
     metrics,
+    query::schema::DatasetSchema,
     types::{
-        dataset,
-        state::{to_ranges, ChunkRef, ChunkSet, Ranges},
+        dataset::{self, Dataset},
+        state::{is_reportable, to_ranges, ChunkRef, ChunkSet, Ranges},
     },
 };
 
@@ -19,7 +31,7 @@ use super::{
     downloader::ChunkDownloader,
     layout::{self, BlockNumber, DataChunk},
     local_fs::{add_temp_prefix, LocalFs},
-    state::{State, UpdateStatus},
+    state::{ChunkInfo, ChunkStatus, State, UpdateStatus},
     Filesystem,
 };
 
@@ -29,42 +41,100 @@ pub struct StateManager {
     state: Mutex<State>,
     notify: tokio::sync::Notify,
     datasets_index: Mutex<DatasetsIndex>,
-    concurrent_downloads: usize,
+    config: Mutex<DownloadsConfig>,
+    downloads_paused: AtomicBool,
+    removal_listeners: Mutex<Vec<Box<dyn Fn(&Path) + Send + Sync>>>,
+    // Block timestamps of the available chunks, ordered by block number within each dataset
+    timestamps: Mutex<HashMap<Dataset, Vec<ChunkTimestamps>>>,
+    // Sizes of the available chunks recorded when they were loaded or downloaded
+    chunk_sizes: Mutex<HashMap<ChunkRef, u64>>,
+    // Disk usage updated by the main loop, so that status requests don't walk the dirs
+    stored_bytes: AtomicU64,
+    dataset_bytes: Mutex<HashMap<Dataset, u64>>,
+    // Assigned chunks that are skipped because they can't be reported, see `is_reportable`
+    unsupported_chunks: Mutex<HashSet<ChunkRef>>,
+}
+
+#[derive(Default)]
+pub struct DatasetStats {
+    pub available_chunks: usize,
+    pub downloading_chunks: usize,
+    pub pending_chunks: usize,
+    pub stored_bytes: u64,
+}
+
+struct ChunkTimestamps {
+    chunk: DataChunk,
+    // First and last block timestamps in seconds
+    first: u64,
+    last: u64,
+}
+
+// Chunk properties read from disk once it's available
+#[derive(Default)]
+struct ChunkStats {
+    size: u64,
+    timestamps: Option<(u64, u64)>,
 }
 
 pub struct Status {
     pub available: Ranges,
     pub downloading: Ranges,
     pub stored_bytes: u64,
+    /// Number of assigned chunks that are not served because their block numbers
+    /// don't fit into the network messages
+    pub unsupported_chunks: usize,
 }
 
 impl StateManager {
-    pub async fn new(workdir: PathBuf, concurrent_downloads: usize) -> Result<Self> {
+    pub async fn new(workdir: PathBuf, config: DownloadsConfig) -> Result<Self> {
         let fs = LocalFs::new(workdir);
         remove_temps(&fs)?;
         let existing_chunks = load_state(&fs).await?;
         debug!("Loaded state: {:?}", existing_chunks);
 
-        Ok(Self {
+        let manager = Self {
             fs,
-            state: Mutex::new(State::new(existing_chunks)),
-            concurrent_downloads,
+            config: Mutex::new(config),
             ..Default::default()
+        };
+        let paths = existing_chunks
+            .iter()
+            .map(|chunk| (chunk.clone(), manager.chunk_path(chunk)))
+            .collect::<Vec<_>>();
+        let stats = tokio::task::spawn_blocking(move || {
+            paths
+                .into_iter()
+                .map(|(chunk, path)| (chunk, read_chunk_stats(&path)))
+                .collect::<Vec<_>>()
         })
+        .await?;
+        for (chunk, stats) in stats {
+            manager.add_chunk_stats(&chunk, stats);
+        }
+        *manager.state.lock() = State::new(existing_chunks);
+        Ok(manager)
     }
 
     pub async fn run(&self, cancellation_token: CancellationToken) {
-        let mut downloader = ChunkDownloader::default();
+        let mut downloader = ChunkDownloader::new(&self.config.lock());
         loop {
             self.state.lock().report_status();
-            let stored_bytes = get_directory_size(&self.fs.root);
-            metrics::STORED_BYTES.set(stored_bytes as i64);
+            self.update_stored_bytes();
 
             tokio::select! {
                 _ = self.notify.notified() => {}
                 (chunk, result) = downloader.downloaded() => {
                     match result {
                         Ok(()) => {
+                            let path = self.chunk_path(&chunk);
+                            let stats = tokio::task::spawn_blocking(move || read_chunk_stats(&path))
+                                .await
+                                .unwrap_or_else(|e| {
+                                    warn!("Couldn't read stats of chunk {chunk}: {e:?}");
+                                    ChunkStats::default()
+                                });
+                            self.add_chunk_stats(&chunk, stats);
                             self.state.lock().complete_download(&chunk, true);
                             metrics::CHUNKS_DOWNLOADED.inc();
                         }
@@ -87,11 +157,21 @@ impl StateManager {
                 info!("Removing chunk {chunk}");
                 self.drop_chunk(&chunk)
                     .unwrap_or_else(|_| panic!("Couldn't remove chunk {chunk}"));
+                let path = self.chunk_path(&chunk);
+                for listener in self.removal_listeners.lock().iter() {
+                    listener(&path);
+                }
+                self.remove_chunk_stats(&chunk);
                 metrics::CHUNKS_REMOVED.inc();
             }
 
+            if self.downloads_paused.load(Ordering::Relaxed) {
+                continue;
+            }
+            let config = self.config.lock().clone();
+            downloader.set_timeouts(&config);
             let index = self.datasets_index.lock();
-            while downloader.download_count() < self.concurrent_downloads {
+            while downloader.download_count() < config.concurrent_downloads {
                 if let Some(chunk) = self.state.lock().take_next_download() {
                     info!("Downloading chunk {chunk}");
                     let dst = self.chunk_path(&chunk);
@@ -103,20 +183,73 @@ impl StateManager {
         }
     }
 
+    /// Downloads that are already running keep their old timeouts.
+    /// If the concurrency is decreased, no new downloads start until enough of them finish.
+    pub fn reload_config(&self, config: DownloadsConfig) {
+        *self.config.lock() = config;
+        self.notify.notify_one();
+    }
+
     #[instrument(skip_all)]
     pub fn current_status(&self) -> Status {
         let status = self.state.lock().status();
-        let stored_bytes = get_directory_size(&self.fs.root);
         Status {
             available: to_ranges(status.available),
             downloading: to_ranges(status.downloading),
-            stored_bytes,
+            stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+            unsupported_chunks: self.unsupported_chunks.lock().len(),
         }
     }
 
+    fn update_stored_bytes(&self) {
+        let entries = match self.fs.root.read_dir_utf8() {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Couldn't read dir '{}': {e:?}", self.fs.root);
+                return;
+            }
+        };
+        let mut total = 0;
+        let mut datasets = HashMap::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Couldn't read dir: {e:?}");
+                    continue;
+                }
+            };
+            let size = get_directory_size(entry.path());
+            total += size;
+            if let Some(dataset) = dataset::decode_dataset(entry.file_name()) {
+                datasets.insert(dataset, size);
+            }
+        }
+        self.stored_bytes.store(total, Ordering::Relaxed);
+        *self.dataset_bytes.lock() = datasets;
+        metrics::STORED_BYTES.set(total as i64);
+    }
+
+    /// Chunks with block numbers that can't be reported to the network are skipped
     // TODO: prevent accidental massive removals
     #[instrument(skip_all)]
-    pub fn set_desired_chunks(&self, desired_chunks: ChunkSet) {
+    pub fn set_desired_chunks(&self, mut desired_chunks: ChunkSet) {
+        let mut unsupported = HashSet::new();
+        desired_chunks.retain(|chunk| {
+            let reportable = is_reportable(&chunk.chunk);
+            if !reportable {
+                unsupported.insert(chunk.clone());
+            }
+            reportable
+        });
+        {
+            let mut previous = self.unsupported_chunks.lock();
+            for chunk in unsupported.difference(&previous) {
+                error!("Assigned chunk {chunk} is not served: block numbers above 2^32 are not supported");
+            }
+            metrics::CHUNKS_UNSUPPORTED.set(unsupported.len() as i64);
+            *previous = unsupported;
+        }
         match self.state.lock().set_desired_chunks(desired_chunks) {
             UpdateStatus::Unchanged => {}
             UpdateStatus::Updated => {
@@ -126,6 +259,11 @@ impl StateManager {
         }
     }
 
+    /// Registers a callback called with the path of each removed chunk
+    pub fn on_chunk_removed(&self, listener: impl Fn(&Path) + Send + Sync + 'static) {
+        self.removal_listeners.lock().push(Box::new(listener));
+    }
+
     pub fn set_datasets_index(&self, index: DatasetsIndex) {
         *self.datasets_index.lock() = index;
     }
@@ -139,6 +277,54 @@ impl StateManager {
         }
     }
 
+    /// Running downloads are not interrupted
+    pub fn set_downloads_paused(&self, paused: bool) {
+        self.downloads_paused.store(paused, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    pub fn downloads_paused(&self) -> bool {
+        self.downloads_paused.load(Ordering::Relaxed)
+    }
+
+    /// Lists available, downloading and pending chunks
+    pub fn list_chunks(&self) -> Vec<ChunkInfo> {
+        self.state.lock().chunks()
+    }
+
+    pub fn dataset_stats(&self) -> BTreeMap<Dataset, DatasetStats> {
+        let mut stats: BTreeMap<Dataset, DatasetStats> = BTreeMap::new();
+        for info in self.list_chunks() {
+            let entry = stats.entry((*info.chunk.dataset).clone()).or_default();
+            match info.status {
+                ChunkStatus::Available => entry.available_chunks += 1,
+                ChunkStatus::Downloading => entry.downloading_chunks += 1,
+                ChunkStatus::Pending => entry.pending_chunks += 1,
+            }
+        }
+        let dataset_bytes = self.dataset_bytes.lock();
+        for (name, entry) in stats.iter_mut() {
+            entry.stored_bytes = dataset_bytes.get(name).copied().unwrap_or(0);
+        }
+        stats
+    }
+
+    /// Size of the available chunk on disk as of when it was downloaded
+    pub fn chunk_size(&self, chunk: &ChunkRef) -> Option<u64> {
+        self.chunk_sizes.lock().get(chunk).copied()
+    }
+
+    /// Removes the chunk from the disk once it's not used by any query.
+    /// Returns `false` if the chunk is not available.
+    pub fn evict_chunk(&self, chunk: &ChunkRef, redownload: bool) -> bool {
+        let evicted = self.state.lock().evict(chunk, redownload);
+        if evicted {
+            info!("Evicting chunk {chunk}");
+            self.notify.notify_one();
+        }
+        evicted
+    }
+
     pub fn find_chunks<'s>(
         &'s self,
         encoded_dataset: &str,
@@ -158,6 +344,52 @@ impl StateManager {
         Ok(guard)
     }
 
+    /// Returns the first block of the earliest available chunk containing blocks
+    /// produced in the given time range (in seconds).
+    /// Chunks whose timestamps couldn't be read are never selected.
+    pub fn find_block_by_timestamp(
+        &self,
+        encoded_dataset: &str,
+        from: u64,
+        to: Option<u64>,
+    ) -> Result<Option<BlockNumber>> {
+        let dataset = dataset::decode_dataset(encoded_dataset)
+            .with_context(|| format!("Couldn't decode dataset: {encoded_dataset}"))?;
+        Ok(self
+            .timestamps
+            .lock()
+            .get(&dataset)
+            .and_then(|chunks| find_by_timestamp(chunks, from, to)))
+    }
+
+    fn add_chunk_stats(&self, chunk: &ChunkRef, stats: ChunkStats) {
+        self.chunk_sizes.lock().insert(chunk.clone(), stats.size);
+        if let Some((first, last)) = stats.timestamps {
+            let mut timestamps = self.timestamps.lock();
+            let chunks = timestamps.entry((*chunk.dataset).clone()).or_default();
+            let pos = chunks.partition_point(|c| c.chunk < chunk.chunk);
+            chunks.insert(
+                pos,
+                ChunkTimestamps {
+                    chunk: chunk.chunk.clone(),
+                    first,
+                    last,
+                },
+            );
+        }
+    }
+
+    fn remove_chunk_stats(&self, chunk: &ChunkRef) {
+        self.chunk_sizes.lock().remove(chunk);
+        let mut timestamps = self.timestamps.lock();
+        if let Some(chunks) = timestamps.get_mut(chunk.dataset.as_ref()) {
+            chunks.retain(|c| c.chunk != chunk.chunk);
+            if chunks.is_empty() {
+                timestamps.remove(chunk.dataset.as_ref());
+            }
+        }
+    }
+
     #[instrument(err, skip(self))]
     fn drop_chunk(&self, chunk: &ChunkRef) -> Result<()> {
         let path = self.chunk_path(chunk);
@@ -219,6 +451,60 @@ async fn load_state(fs: &LocalFs) -> Result<ChunkSet> {
     Ok(result)
 }
 
+// Relies on block timestamps being non-decreasing across the chunks of a dataset
+fn find_by_timestamp(
+    chunks: &[ChunkTimestamps],
+    from: u64,
+    to: Option<u64>,
+) -> Option<BlockNumber> {
+    let pos = chunks.partition_point(|c| c.last < from);
+    chunks
+        .get(pos)
+        .filter(|c| to.map_or(true, |to| c.first <= to))
+        .map(|c| c.chunk.first_block)
+}
+
+fn read_chunk_stats(path: &Path) -> ChunkStats {
+    let timestamps = read_timestamp_range(path).unwrap_or_else(|e| {
+        warn!("Couldn't read block timestamps of chunk {path}: {e:?}");
+        None
+    });
+    ChunkStats {
+        size: get_directory_size(path),
+        timestamps,
+    }
+}
+
+/// Reads the block timestamps range from the parquet statistics of the blocks table
+fn read_timestamp_range(chunk_path: &Path) -> Result<Option<(u64, u64)>> {
+    let schema = DatasetSchema::load(chunk_path)?;
+    let Some(blocks) = schema.tables.iter().find(|table| table.name == "blocks") else {
+        return Ok(None);
+    };
+    let reader = SerializedFileReader::new(std::fs::File::open(chunk_path.join(&blocks.file))?)?;
+    let metadata = reader.metadata();
+    let Some(column) = metadata
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .position(|column| column.name() == "timestamp")
+    else {
+        return Ok(None);
+    };
+    let mut range: Option<(i64, i64)> = None;
+    for row_group in metadata.row_groups() {
+        if let Some(Statistics::Int64(stats)) = row_group.column(column).statistics() {
+            if stats.has_min_max_set() {
+                let (min, max) = range.unwrap_or((*stats.min(), *stats.max()));
+                range = Some((min.min(*stats.min()), max.max(*stats.max())));
+            }
+        }
+    }
+    // Timestamps are stored in milliseconds
+    Ok(range.map(|(min, max)| ((min / 1000) as u64, (max / 1000) as u64)))
+}
+
 fn get_directory_size(path: &Path) -> u64 {
     let mut result = 0;
     for entry in walkdir::WalkDir::new(path) {
@@ -245,6 +531,38 @@ fn get_directory_size(path: &Path) -> u64 {
 mod tests {
     use camino::Utf8PathBuf as PathBuf;
 
+    use crate::{storage::layout::DataChunk, util::tests::tests_data};
+
+    #[test]
+    fn test_read_timestamp_range() {
+        let chunk = tests_data().join("0017881390/0017881390-0017882786-32ee9457");
+        let (first, last) = super::read_timestamp_range(&chunk).unwrap().unwrap();
+        assert!(first <= last);
+        // Block 17881390 was produced in August 2023
+        assert!((1690848000..1693526400).contains(&first));
+    }
+
+    #[test]
+    fn test_find_by_timestamp() {
+        let chunk = |first_block: u64, first: u64, last: u64| super::ChunkTimestamps {
+            chunk: DataChunk {
+                first_block: first_block.into(),
+                last_block: (first_block + 9).into(),
+                ..Default::default()
+            },
+            first,
+            last,
+        };
+        let chunks = [chunk(0, 100, 200), chunk(10, 210, 300), chunk(20, 310, 400)];
+        let find = |from, to| super::find_by_timestamp(&chunks, from, to);
+        assert_eq!(find(50, None), Some(0.into()));
+        assert_eq!(find(200, None), Some(0.into()));
+        assert_eq!(find(205, None), Some(10.into()));
+        assert_eq!(find(205, Some(208)), None);
+        assert_eq!(find(205, Some(210)), Some(10.into()));
+        assert_eq!(find(401, None), None);
+    }
+
     #[test]
     fn test_join_glob() {
         // `remove_temps` depends on this behavior
@@ -18,6 +18,7 @@ pub struct State {
     desired: ChunkSet,
     to_download: ChunkSet, // to_download is always equal to desired.diff(available).diff(downloading)
     locks: BTreeMap<ChunkRef, u8>, // stores ref count for each chunk
+    evicted: ChunkSet,     // available chunks to be removed even if they are desired
 }
 
 #[derive(Debug)]
@@ -31,6 +32,20 @@ pub struct Status {
     pub downloading: ChunkSet,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkStatus {
+    Available,
+    Downloading,
+    Pending,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkInfo {
+    pub chunk: ChunkRef,
+    pub status: ChunkStatus,
+    pub locks: u8,
+}
+
 impl State {
     pub fn new(available: ChunkSet) -> Self {
         Self {
@@ -89,16 +104,59 @@ impl State {
     pub fn take_removals(&mut self) -> Vec<ChunkRef> {
         let mut result = Vec::new();
         self.available.retain(|chunk| {
-            if self.desired.contains(chunk) || self.locks.contains_key(chunk) {
+            let keep = self.desired.contains(chunk) && !self.evicted.contains(chunk);
+            if keep || self.locks.contains_key(chunk) {
                 true
             } else {
                 result.push(chunk.clone());
                 false
             }
         });
+        for chunk in result.iter() {
+            if self.evicted.remove(chunk) && self.desired.contains(chunk) {
+                self.to_download.insert(chunk.clone());
+            }
+        }
         result
     }
 
+    /// Schedules removal of an available chunk. Locked chunks are removed once released.
+    /// If `redownload` is set, the chunk stays desired and gets downloaded again,
+    /// otherwise it's only downloaded again if it comes with a new assignment.
+    pub fn evict(&mut self, chunk: &ChunkRef, redownload: bool) -> bool {
+        if !self.available.contains(chunk) {
+            return false;
+        }
+        if redownload {
+            self.evicted.insert(chunk.clone());
+        } else {
+            self.desired.remove(chunk);
+        }
+        true
+    }
+
+    pub fn chunks(&self) -> Vec<ChunkInfo> {
+        let info = |chunk: &ChunkRef, status| ChunkInfo {
+            chunk: chunk.clone(),
+            status,
+            locks: self.locks.get(chunk).copied().unwrap_or(0),
+        };
+        self.available
+            .iter()
+            .map(|chunk| info(chunk, ChunkStatus::Available))
+            .chain(
+                self.downloading
+                    .iter()
+                    .map(|chunk| info(chunk, ChunkStatus::Downloading)),
+            )
+            .chain(
+                self.to_download
+                    .iter()
+                    .map(|chunk| info(chunk, ChunkStatus::Pending)),
+            )
+            .collect()
+    }
+
     // Only works as a hint to speed up things.
     // Cancelled downloads still have to be reported with a `complete_download` call
     pub fn get_stale_downloads(&self) -> Vec<ChunkRef> {
@@ -139,7 +197,7 @@ impl State {
             None => return Vec::new(),
             Some(chunk) => chunk.clone(),
         };
-        if first.chunk.first_block > block_number {
+        if first.dataset != dataset || first.chunk.first_block > block_number {
             return Vec::new();
         }
 
@@ -261,6 +319,68 @@ mod tests {
         assert_eq!(state.status().downloading.into_iter().collect_vec(), &[]);
     }
 
+    #[test]
+    fn test_evict() {
+        let ds = Arc::new("ds".to_owned());
+        let chunk_ref = |x| ChunkRef {
+            dataset: ds.clone(),
+            chunk: DataChunk::from_path(&format!(
+                "0000000000/000000000{}-000000000{}-00000000",
+                x,
+                x + 1
+            ))
+            .unwrap(),
+        };
+        let a = chunk_ref(0);
+        let b = chunk_ref(2);
+        let c = chunk_ref(4);
+
+        let mut state = State::new([a.clone(), b.clone()].into_iter().collect());
+        assert!(!state.evict(&c, false));
+        assert!(state.evict(&a, false));
+        assert!(state.evict(&b, true));
+        assert_eq!(
+            state.find_and_lock_chunks(ds.clone(), 2.into()),
+            &[b.clone()]
+        );
+
+        // Locked chunk is kept until released
+        assert_eq!(state.take_removals(), &[a.clone()]);
+        assert_eq!(state.take_next_download(), None);
+        assert_eq!(state.chunks()[0].locks, 1);
+
+        state.release_chunks([b.clone()]);
+        assert_eq!(state.take_removals(), &[b.clone()]);
+        assert_eq!(state.chunks()[0].status, super::ChunkStatus::Pending);
+        assert_eq!(state.take_next_download(), Some(b.clone()));
+        assert_eq!(state.take_next_download(), None);
+    }
+
+    #[test]
+    fn test_find_chunks() {
+        let chunk_ref = |ds: &str, path: &str| ChunkRef {
+            dataset: Arc::new(ds.to_owned()),
+            chunk: DataChunk::from_path(path).unwrap(),
+        };
+        let a = chunk_ref("a", "0000000000/0000000000-0000000009-00000000");
+        let b = chunk_ref("b", "5000000000/5000000000-5000000009-00000000");
+        let c = chunk_ref("b", "5000000000/5000000010-5000000019-00000000");
+
+        let mut state = State::new([a.clone(), b.clone(), c.clone()].into_iter().collect());
+        assert_eq!(
+            state.find_and_lock_chunks(b.dataset.clone(), 5_000_000_005.into()),
+            &[b.clone(), c.clone()]
+        );
+        assert_eq!(
+            state.find_and_lock_chunks(a.dataset.clone(), 5_000_000_005.into()),
+            &[]
+        );
+        assert_eq!(
+            state.find_and_lock_chunks(a.dataset.clone(), 5.into()),
+            &[a.clone()]
+        );
+    }
+
     #[test]
     fn test_data_chunk_comparison() {
         // Chunks lookup depends on sorting by last_block
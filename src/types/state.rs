@@ -28,6 +28,13 @@ impl std::fmt::Display for ChunkRef {
     }
 }
 
+/// Ranges in the network messages only support 32-bit block numbers,
+/// so chunks with larger block numbers can't be reported and are not downloaded
+pub fn is_reportable(chunk: &DataChunk) -> bool {
+    u32::try_from(*chunk.last_block).is_ok()
+}
+
+/// Chunks that are not [reportable](is_reportable) are skipped
 pub fn to_ranges(state: ChunkSet) -> Ranges {
     state
         .into_iter()
@@ -36,7 +43,13 @@ pub fn to_ranges(state: ChunkSet) -> Ranges {
         .map(|(dataset, chunks)| {
             let range: RangeSet = chunks
                 .into_iter()
-                .map(|chunk| Range::new(*chunk.chunk.first_block, *chunk.chunk.last_block))
+                .filter(|chunk| is_reportable(&chunk.chunk))
+                .map(|chunk| {
+                    Range::new(
+                        *chunk.chunk.first_block as u32,
+                        *chunk.chunk.last_block as u32,
+                    )
+                })
                 .into();
             ((*dataset).clone(), range)
         })
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+/// Multi-producer single-consumer queue that keeps a separate bounded queue for each key
+/// and pops items using deficit round robin.
+/// A key gets up to `w / w_min` items popped per round, where `w` is its weight
+/// and `w_min` is the smallest weight among the keys with queued items,
+/// but no more than [`MAX_QUANTUM`], so that a heavy key can't hold the head of the queue.
+pub struct FairQueue<K, T> {
+    inner: Mutex<Inner<K, T>>,
+    notify: Notify,
+    capacity: AtomicUsize,
+    total_capacity: AtomicUsize,
+}
+
+/// Max number of items popped from a single key per round
+pub const MAX_QUANTUM: u64 = 10;
+
+struct Inner<K, T> {
+    queues: HashMap<K, SubQueue<T>>,
+    active: VecDeque<K>, // keys with non-empty queues in the round robin order
+    len: usize,
+}
+
+struct SubQueue<T> {
+    items: VecDeque<T>,
+    weight: u64,
+    deficit: u64,
+}
+
+impl<K: Hash + Eq + Clone, T> FairQueue<K, T> {
+    /// `capacity` limits the number of queued items for each key
+    /// and `total_capacity` limits the number of queued items of all keys together
+    pub fn new(capacity: usize, total_capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                queues: HashMap::new(),
+                active: VecDeque::new(),
+                len: 0,
+            }),
+            notify: Notify::new(),
+            capacity: AtomicUsize::new(capacity),
+            total_capacity: AtomicUsize::new(total_capacity),
+        }
+    }
+
+    /// Already queued items are kept even if they exceed the new capacity
+    pub fn set_capacity(&self, capacity: usize, total_capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        self.total_capacity.store(total_capacity, Ordering::Relaxed);
+    }
+
+    /// Returns the new length of the key's queue or the item back if the queue is full
+    pub fn try_push(&self, key: K, weight: u64, item: T) -> Result<usize, T> {
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        if inner.len >= self.total_capacity.load(Ordering::Relaxed) {
+            return Err(item);
+        }
+        let queue = inner.queues.entry(key.clone()).or_insert_with(|| SubQueue {
+            items: VecDeque::new(),
+            weight: 1,
+            deficit: 0,
+        });
+        if queue.items.len() >= self.capacity.load(Ordering::Relaxed) {
+            if queue.items.is_empty() {
+                inner.queues.remove(&key);
+            }
+            return Err(item);
+        }
+        if queue.items.is_empty() {
+            inner.active.push_back(key);
+        }
+        queue.weight = weight.max(1);
+        queue.items.push_back(item);
+        inner.len += 1;
+        let len = queue.items.len();
+        self.notify.notify_one();
+        Ok(len)
+    }
+
+    pub fn len(&self, key: &K) -> usize {
+        self.inner
+            .lock()
+            .queues
+            .get(key)
+            .map(|queue| queue.items.len())
+            .unwrap_or(0)
+    }
+
+    pub fn total_len(&self) -> usize {
+        self.inner.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().active.is_empty()
+    }
+
+    /// Waits for the next item. Should only be called from a single task at a time.
+    pub async fn pop(&self) -> (K, T) {
+        loop {
+            if let Some(result) = self.try_pop() {
+                return result;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn try_pop(&self) -> Option<(K, T)> {
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        let key = inner.active.front()?.clone();
+        let min_weight = if inner.queues[&key].deficit == 0 {
+            // The key's turn has just started
+            inner.queues.values().map(|queue| queue.weight).min()
+        } else {
+            None
+        };
+        let queue = inner
+            .queues
+            .get_mut(&key)
+            .expect("Active key should have a queue");
+        if let Some(min_weight) = min_weight {
+            queue.deficit = (queue.weight / min_weight).clamp(1, MAX_QUANTUM);
+        }
+        let item = queue
+            .items
+            .pop_front()
+            .expect("Active queue shouldn't be empty");
+        queue.deficit -= 1;
+        inner.len -= 1;
+        if queue.items.is_empty() {
+            inner.active.pop_front();
+            inner.queues.remove(&key);
+        } else if queue.deficit == 0 {
+            inner.active.rotate_left(1);
+        }
+        Some((key, item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FairQueue, MAX_QUANTUM};
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let queue = FairQueue::new(10, 100);
+        for i in 0..3 {
+            queue.try_push("a", 1, i).unwrap();
+        }
+        queue.try_push("b", 1, 10).unwrap();
+        queue.try_push("c", 1, 20).unwrap();
+        queue.try_push("c", 1, 21).unwrap();
+
+        let mut popped = Vec::new();
+        while !queue.is_empty() {
+            popped.push(queue.pop().await);
+        }
+        assert_eq!(
+            popped,
+            [
+                ("a", 0),
+                ("b", 10),
+                ("c", 20),
+                ("a", 1),
+                ("c", 21),
+                ("a", 2)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_weights() {
+        let queue = FairQueue::new(10, 100);
+        for i in 0..4 {
+            queue.try_push("a", 1, i).unwrap();
+            queue.try_push("b", 3, 10 + i).unwrap();
+        }
+
+        let mut popped = Vec::new();
+        while !queue.is_empty() {
+            popped.push(queue.pop().await);
+        }
+        assert_eq!(
+            popped,
+            [
+                ("a", 0),
+                ("b", 10),
+                ("b", 11),
+                ("b", 12),
+                ("a", 1),
+                ("b", 13),
+                ("a", 2),
+                ("a", 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_capacity() {
+        let queue = FairQueue::new(2, 4);
+        assert_eq!(queue.try_push("a", 1, 0), Ok(1));
+        assert_eq!(queue.try_push("a", 1, 1), Ok(2));
+        assert_eq!(queue.try_push("a", 1, 2), Err(2));
+        assert_eq!(queue.try_push("b", 1, 3), Ok(1));
+        assert_eq!(queue.len(&"a"), 2);
+        assert_eq!(queue.len(&"c"), 0);
+
+        assert_eq!(queue.try_push("c", 1, 4), Ok(1));
+        assert_eq!(queue.try_push("d", 1, 5), Err(5));
+        assert_eq!(queue.total_len(), 4);
+
+        queue.set_capacity(1, 10);
+        assert_eq!(queue.try_push("b", 1, 6), Err(6));
+        assert_eq!(queue.try_push("d", 1, 7), Ok(1));
+        assert_eq!(queue.len(&"a"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_heavy_key_doesnt_starve_others() {
+        let queue = FairQueue::new(100, 100);
+        for i in 0..50 {
+            queue.try_push("heavy", 1_000_000, i).unwrap();
+        }
+        queue.try_push("light", 1, 100).unwrap();
+        queue.try_push("light", 1, 101).unwrap();
+
+        let mut popped = Vec::new();
+        for _ in 0..2 * (MAX_QUANTUM as usize + 1) {
+            let (key, item) = queue.pop().await;
+            popped.push((key, item));
+            // The heavy key keeps its queue full
+            if key == "heavy" {
+                queue.try_push("heavy", 1_000_000, item + 50).unwrap();
+            }
+        }
+        let light = popped
+            .iter()
+            .filter(|(key, _)| *key == "light")
+            .map(|(_, item)| *item)
+            .collect::<Vec<_>>();
+        assert_eq!(light, [100, 101]);
+    }
+}
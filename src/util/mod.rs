@@ -1,6 +1,10 @@
+pub mod fair_queue;
 pub mod hash;
 pub mod iterator;
 mod once;
+pub mod rate_limiter;
+pub mod readiness;
+pub mod supervisor;
 pub mod tests;
 
 pub type UseOnce<T> = once::UseOnce<T>;
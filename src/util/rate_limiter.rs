@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Token bucket rate limiter with a separate bucket for each key
+pub struct RateLimiter<K> {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets<K>>,
+}
+
+struct Buckets<K> {
+    buckets: HashMap<K, Bucket>,
+    cleaned_at: Option<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    /// Allows `rate` requests per second on average and up to `burst` requests at once
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1.0),
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                cleaned_at: None,
+            }),
+        }
+    }
+
+    /// Time it takes an empty bucket to become full again
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.burst / self.rate)
+    }
+
+    pub fn try_acquire(&self, key: K) -> bool {
+        self.try_acquire_at(key, Instant::now())
+    }
+
+    fn try_acquire_at(&self, key: K, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock();
+        let buckets = &mut *buckets;
+        // Buckets that have been idle long enough are full, so they can be dropped
+        // and recreated on the next request
+        let refill_time = self.refill_time();
+        let cleaned_at = *buckets.cleaned_at.get_or_insert(now);
+        if now.saturating_duration_since(cleaned_at) >= refill_time {
+            buckets
+                .buckets
+                .retain(|_, bucket| now.saturating_duration_since(bucket.updated_at) < refill_time);
+            buckets.cleaned_at = Some(now);
+        }
+        let bucket = buckets.buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2.0, 2.0);
+        let start = Instant::now();
+        assert!(limiter.try_acquire_at("a", start));
+        assert!(limiter.try_acquire_at("a", start));
+        assert!(!limiter.try_acquire_at("a", start));
+        assert!(limiter.try_acquire_at("b", start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.try_acquire_at("a", later));
+        assert!(!limiter.try_acquire_at("a", later));
+
+        let much_later = start + Duration::from_secs(100);
+        assert!(limiter.try_acquire_at("a", much_later));
+        assert!(limiter.try_acquire_at("a", much_later));
+        assert!(!limiter.try_acquire_at("a", much_later));
+    }
+
+    #[test]
+    fn test_idle_buckets_eviction() {
+        let limiter = RateLimiter::new(1.0, 2.0);
+        let start = Instant::now();
+        assert!(limiter.try_acquire_at("a", start));
+        assert!(limiter.try_acquire_at("b", start + Duration::from_secs(1)));
+        assert_eq!(limiter.buckets.lock().buckets.len(), 2);
+
+        assert!(limiter.try_acquire_at("c", start + Duration::from_millis(2500)));
+        assert_eq!(limiter.buckets.lock().buckets.len(), 2);
+        assert!(!limiter.buckets.lock().buckets.contains_key("a"));
+
+        // The evicted key starts with a full bucket
+        let later = start + Duration::from_secs(3);
+        assert!(limiter.try_acquire_at("a", later));
+        assert!(limiter.try_acquire_at("a", later));
+        assert!(!limiter.try_acquire_at("a", later));
+    }
+}
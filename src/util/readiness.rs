@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+
+use parking_lot::Mutex;
+
+/// Named conditions that all have to be met for the worker to be ready to serve queries.
+/// Without any conditions registered the worker is always ready.
+#[derive(Default)]
+pub struct Readiness {
+    conditions: Mutex<BTreeMap<&'static str, bool>>,
+}
+
+impl Readiness {
+    /// Registers the condition if it's not known yet and updates its state
+    pub fn set(&self, condition: &'static str, met: bool) {
+        self.conditions.lock().insert(condition, met);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.conditions.lock().values().all(|met| *met)
+    }
+
+    pub fn unmet_conditions(&self) -> Vec<&'static str> {
+        self.conditions
+            .lock()
+            .iter()
+            .filter(|(_, met)| !**met)
+            .map(|(condition, _)| *condition)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Readiness;
+
+    #[test]
+    fn test_readiness() {
+        let readiness = Readiness::default();
+        assert!(readiness.is_ready());
+
+        readiness.set("a", false);
+        readiness.set("b", true);
+        assert!(!readiness.is_ready());
+        assert_eq!(readiness.unmet_conditions(), ["a"]);
+
+        readiness.set("a", true);
+        assert!(readiness.is_ready());
+        assert!(readiness.unmet_conditions().is_empty());
+    }
+}
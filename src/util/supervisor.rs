@@ -0,0 +1,139 @@
+use std::panic::AssertUnwindSafe;
+
+use anyhow::{anyhow, Result};
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, Future, FutureExt, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
+
+use crate::metrics::{self, TaskExitReason};
+
+/// Runs a group of long-living tasks that are supposed to work until the cancellation token
+/// is triggered. If any of them exits earlier (finishes, fails or panics),
+/// the token gets cancelled so that all other tasks shut down too.
+pub struct Supervisor<'a> {
+    cancellation_token: CancellationToken,
+    tasks: Vec<(&'static str, LocalBoxFuture<'a, Result<()>>)>,
+}
+
+impl<'a> Supervisor<'a> {
+    pub fn new(cancellation_token: CancellationToken) -> Self {
+        Self {
+            cancellation_token,
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn with_task(
+        mut self,
+        name: &'static str,
+        task: impl Future<Output = Result<()>> + 'a,
+    ) -> Self {
+        self.tasks.push((name, task.boxed_local()));
+        self
+    }
+
+    /// Waits for all the tasks to finish.
+    /// Returns an error if any of them exited before the cancellation token was triggered.
+    pub async fn run(self) -> Result<()> {
+        let mut tasks: FuturesUnordered<_> = self
+            .tasks
+            .into_iter()
+            .map(|(name, task)| {
+                AssertUnwindSafe(task)
+                    .catch_unwind()
+                    .map(move |result| (name, result))
+            })
+            .collect();
+
+        let mut failure = None;
+        while let Some((name, result)) = tasks.next().await {
+            if self.cancellation_token.is_cancelled() {
+                if let Ok(Err(e)) = result {
+                    warn!("Task {name} failed during shutdown: {e:?}");
+                }
+                continue;
+            }
+            let (reason, err) = match result {
+                Ok(Ok(())) => (
+                    TaskExitReason::Finished,
+                    anyhow!("Task {name} finished unexpectedly"),
+                ),
+                Ok(Err(e)) => (
+                    TaskExitReason::Failed,
+                    e.context(format!("Task {name} failed")),
+                ),
+                Err(panic) => (
+                    TaskExitReason::Panicked,
+                    anyhow!("Task {name} panicked: {}", panic_message(&*panic)),
+                ),
+            };
+            error!("{err:?}. Stopping all other tasks");
+            metrics::task_exited(name, reason);
+            failure.get_or_insert(err);
+            self.cancellation_token.cancel();
+        }
+
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.as_str()
+    } else {
+        "unknown reason"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use tokio_util::sync::CancellationToken;
+
+    use super::Supervisor;
+
+    #[tokio::test]
+    async fn test_cancelled() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = Supervisor::new(token.clone())
+            .with_task("a", token.child_token().cancelled_owned().map(Ok))
+            .with_task("b", token.child_token().cancelled_owned().map(Ok))
+            .run()
+            .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_task_finished() {
+        let token = CancellationToken::new();
+        let result = Supervisor::new(token.clone())
+            .with_task("waiting", token.child_token().cancelled_owned().map(Ok))
+            .with_task("finished", async { Ok(()) })
+            .run()
+            .await;
+        assert!(token.is_cancelled());
+        assert!(result.unwrap_err().to_string().contains("finished"));
+    }
+
+    #[tokio::test]
+    async fn test_task_panicked() {
+        let token = CancellationToken::new();
+        let result = Supervisor::new(token.clone())
+            .with_task("waiting", token.child_token().cancelled_owned().map(Ok))
+            .with_task("panicked", async {
+                let value: Option<()> = None;
+                value.expect("oops");
+                Ok(())
+            })
+            .run()
+            .await;
+        assert!(token.is_cancelled());
+        assert!(result.unwrap_err().to_string().contains("oops"));
+    }
+}